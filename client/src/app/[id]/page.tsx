@@ -1,6 +1,6 @@
 'use client'
 import { useState, useEffect } from 'react'
-import { useParams } from 'next/navigation'
+import { useParams, useSearchParams } from 'next/navigation'
 import { motion, AnimatePresence } from 'framer-motion'
 import { Clock, Copy, Check, QrCode, AlertTriangle } from 'lucide-react'
 import { QRCodeSVG } from 'qrcode.react'
@@ -25,6 +25,8 @@ const LitecoinIcon = ({ className = "h-6 w-6" }) => (
 
 export default function Page() {
     const { id } = useParams()
+    // per-payment read token returned by POST /payments, passed as ?token=
+    const token = useSearchParams().get('token')
     const [data, setData] = useState(null)
     const [loading, setLoading] = useState(true)
     const [error, setError] = useState(false)
@@ -124,6 +126,7 @@ export default function Page() {
         };
 
         switch (status.toLowerCase()) {
+            case 'created':
             case 'pending':
                 return {
                     color: '#eab308',
@@ -131,6 +134,24 @@ export default function Page() {
                     label: 'Pending Payment',
                     icon: <Clock className="h-4 w-4" />
                 };
+            case 'seen_in_mempool':
+            case 'confirming':
+                return {
+                    color: '#3b82f6',
+                    bgColor: 'rgba(30, 58, 138, 0.3)',
+                    label: 'Confirming',
+                    icon: <Clock className="h-4 w-4" />
+                };
+            case 'underpaid':
+                return {
+                    color: '#f97316',
+                    bgColor: 'rgba(124, 45, 18, 0.3)',
+                    label: 'Underpaid',
+                    icon: <AlertTriangle className="h-4 w-4" />
+                };
+            case 'paid':
+            case 'overpaid':
+            case 'swept':
             case 'completed':
                 return {
                     color: '#22c55e',
@@ -138,6 +159,13 @@ export default function Page() {
                     label: 'Payment Confirmed',
                     icon: <Check className="h-4 w-4" />
                 };
+            case 'paid_late':
+                return {
+                    color: '#f97316',
+                    bgColor: 'rgba(124, 45, 18, 0.3)',
+                    label: 'Received After Expiry',
+                    icon: <AlertTriangle className="h-4 w-4" />
+                };
             case 'expired':
                 return {
                     color: '#ef4444',
@@ -187,7 +215,7 @@ export default function Page() {
                         amount: '0.25',
                         usdAmount: '19.99',
                         address: 'MKL45asdJK23lkj5235lkjfsSADFja',
-                        status: 'pending',
+                        status: 'created',
                         created_at: new Date(now.getTime() - 300000).toISOString(), // 5 minutes ago
                         expires_at: new Date(now.getTime() + 900000).toISOString()  // 15 minutes from now
                     };
@@ -196,7 +224,9 @@ export default function Page() {
                     setData(mockData);
                     setError(false);
                 } else {
-                    const r = await fetch(`${API}/payments/${id}`);
+                    const r = await fetch(`${API}/payments/${id}`, {
+                        headers: token ? { Authorization: `Bearer ${token}` } : {}
+                    });
                     if (r.ok) {
                         const jsonData = await r.json();
                         console.log('API response:', jsonData);
@@ -221,12 +251,42 @@ export default function Page() {
 
         load();
 
-        const t = setInterval(() => {
-            load();
-        }, 5000);
+        // live updates over Server-Sent Events; poll only while the stream is down
+        let poll = null;
+        const startPolling = () => {
+            if (!poll) poll = setInterval(load, 5000);
+        };
+        const stopPolling = () => {
+            if (poll) clearInterval(poll);
+            poll = null;
+        };
+        if (!API || API === 'undefined' || typeof EventSource === 'undefined') {
+            startPolling();
+            return stopPolling;
+        }
 
-        return () => clearInterval(t);
-    }, [id]);
+        // EventSource cannot send headers, so the read token goes in the query
+        const query = token ? `?token=${encodeURIComponent(token)}` : '';
+        const events = new EventSource(`${API}/payments/${id}/events${query}`);
+        events.onopen = stopPolling;
+        // the browser keeps retrying the stream meanwhile
+        events.onerror = startPolling;
+        events.addEventListener('payment', (e) => {
+            const update = JSON.parse(e.data);
+            delete update.payment_id;
+            setData(prev => prev ? { ...prev, ...update } : prev);
+            setRefreshPulse(true);
+            setTimeout(() => setRefreshPulse(false), 1000);
+            setLastRefresh(0);
+            // the server ends the stream here; don't let the browser reopen it
+            if (update.status === 'swept') events.close();
+        });
+
+        return () => {
+            events.close();
+            stopPolling();
+        };
+    }, [id, token]);
 
     const timeData = data ? getTimeData(data.created_at, data.expires_at) : {
         remainingMs: 0,
@@ -0,0 +1,28 @@
+import { NextResponse } from 'next/server'
+
+const API = process.env.NEXT_PUBLIC_API_URL
+// payments:create key; only read on the server, never sent to the browser
+const API_KEY = process.env.LITEGATE_API_KEY
+
+export async function POST(req: Request) {
+  if (!API || !API_KEY) {
+    return NextResponse.json(
+      { error: 'NEXT_PUBLIC_API_URL and LITEGATE_API_KEY must be set' },
+      { status: 500 }
+    )
+  }
+
+  const r = await fetch(`${API}/payments`, {
+    method: 'POST',
+    headers: {
+      'Content-Type': 'application/json',
+      Authorization: `Bearer ${API_KEY}`
+    },
+    body: await req.text()
+  })
+
+  return new NextResponse(await r.text(), {
+    status: r.status,
+    headers: { 'Content-Type': 'application/json' }
+  })
+}
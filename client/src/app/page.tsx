@@ -4,7 +4,6 @@ import { useRouter } from 'next/navigation'
 import { Clock, ArrowRight, Info, Check, CreditCard, AlertTriangle } from 'lucide-react'
 import { motion, AnimatePresence } from 'framer-motion'
 
-const API = process.env.NEXT_PUBLIC_API_URL
 const EXPIRATION_OPTIONS = [
   { label: '15m', value: '900' },
   { label: '1h', value: '3600' },
@@ -196,21 +195,22 @@ export default function Page() {
     setFormState('loading')
 
     try {
-      const r = await fetch(`${API}/payments`, {
+      // created through our own server route, which holds the API key
+      const r = await fetch('/api/payments', {
         method: 'POST',
         headers: { 'Content-Type': 'application/json' },
-        body: JSON.stringify({ amount: Number(amount), ttl: Number(ttl) })
+        body: JSON.stringify({ amount, ttl: Number(ttl) })
       })
 
       if (!r.ok) {
         throw new Error('Payment creation failed')
       }
 
-      const { id } = await r.json()
+      const { id, read_token } = await r.json()
       setFormState('success')
 
       setTimeout(() => {
-        router.push(`/${id}`)
+        router.push(`/${id}?token=${encodeURIComponent(read_token)}`)
       }, 1000)
     } catch (error) {
       setFormState('error')
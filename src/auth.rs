@@ -0,0 +1,226 @@
+use crate::db::Db;
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, Method},
+    middleware::Next,
+    web, Error, HttpMessage, HttpResponse,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, fmt, str::FromStr};
+use tokio::task::spawn_blocking;
+use tracing::{debug, error};
+
+/// What an API key may do. `Admin` implies every other scope and reaches
+/// across merchants; the others are confined to the key's merchant.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    #[serde(rename = "payments:create")]
+    CreatePayments,
+    #[serde(rename = "payments:read")]
+    ReadPayments,
+    #[serde(rename = "webhooks")]
+    Webhooks,
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Self; 4] = [
+        Self::CreatePayments,
+        Self::ReadPayments,
+        Self::Webhooks,
+        Self::Admin,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::CreatePayments => "payments:create",
+            Self::ReadPayments => "payments:read",
+            Self::Webhooks => "webhooks",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|sc| sc.as_str() == s)
+            .ok_or_else(|| format!("unknown scope {s:?}"))
+    }
+}
+
+/// Who is calling, once their bearer token checked out.
+#[derive(Debug, Clone)]
+pub enum Caller {
+    /// A stored API key
+    Key {
+        id: String,
+        scopes: Vec<Scope>,
+        merchant_id: String,
+    },
+    /// The read token handed out with one payment, for its checkout page
+    Payment { payment_id: String },
+}
+
+impl Caller {
+    /// Merchant whose data this caller is confined to; `None` for admin keys,
+    /// which see every merchant, and for payment tokens, which are already
+    /// confined to one payment.
+    pub fn merchant_scope(&self) -> Option<&str> {
+        match self {
+            Self::Key { scopes, .. } if scopes.contains(&Scope::Admin) => None,
+            Self::Key { merchant_id, .. } => Some(merchant_id),
+            Self::Payment { .. } => None,
+        }
+    }
+
+    fn allows(&self, scope: Scope, payment_id: Option<&str>) -> bool {
+        match self {
+            Self::Key { scopes, .. } => scopes.contains(&Scope::Admin) || scopes.contains(&scope),
+            Self::Payment { payment_id: own } => {
+                scope == Scope::ReadPayments && payment_id == Some(own.as_str())
+            }
+        }
+    }
+}
+
+/// Tokens are random, so a plain SHA-256 is enough to keep them out of the DB.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn random_token(prefix: &str, bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    rand::thread_rng().fill_bytes(&mut buf);
+    format!("{prefix}{}", hex::encode(buf))
+}
+
+/// A new API key; only its hash is stored.
+pub fn generate_api_key() -> String {
+    random_token("lg_", 32)
+}
+
+/// A new read-only token for one payment's checkout page.
+pub fn generate_payment_token() -> String {
+    random_token("lgp_", 24)
+}
+
+/// Scope a route needs, by method and matched route pattern; `None` is public.
+fn required_scope(method: &Method, pattern: &str) -> Option<Scope> {
+    match (method.as_str(), pattern) {
+        ("POST", "/payments") => Some(Scope::CreatePayments),
+        ("GET", "/payments") | ("GET", "/payments/{id}") => Some(Scope::ReadPayments),
+        ("GET", "/payments/{id}/events") | ("GET", "/payments/{id}/ws") => {
+            Some(Scope::ReadPayments)
+        }
+        ("POST", "/payments/{id}/late") => Some(Scope::CreatePayments),
+        (_, p) if p.starts_with("/webhooks") => Some(Scope::Webhooks),
+        (_, p) if p.starts_with("/psbts") => Some(Scope::Admin),
+        _ => None,
+    }
+}
+
+/// Live streams are opened by `EventSource` / `WebSocket`, which cannot set
+/// headers, so they also take the token as `?token=`.
+fn live_stream(pattern: &str) -> bool {
+    pattern == "/payments/{id}/events" || pattern == "/payments/{id}/ws"
+}
+
+fn query_token(query: &str) -> Option<String> {
+    web::Query::<HashMap<String, String>>::from_query(query)
+        .ok()?
+        .into_inner()
+        .remove("token")
+        .filter(|t| !t.is_empty())
+}
+
+/// Checks the `Authorization: Bearer` token of every non-public route:
+/// 401 when missing or unknown, 403 when it lacks the route's scope. A
+/// payment read token only opens `GET /payments/{id}` of its own payment
+/// and that payment's live streams.
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let Some(pattern) = req.match_pattern() else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    };
+    let Some(scope) = required_scope(req.method(), &pattern) else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_left_body);
+    };
+    // the route is matched but its params are not extracted yet at this point
+    let payment_id = pattern
+        .split('/')
+        .position(|segment| segment == "{id}")
+        .and_then(|i| req.path().split('/').nth(i))
+        .map(str::to_string);
+
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim().to_string())
+        .or_else(|| {
+            live_stream(&pattern)
+                .then(|| query_token(req.query_string()))
+                .flatten()
+        });
+    let Some(token) = token else {
+        return Ok(req.into_response(HttpResponse::Unauthorized().finish().map_into_right_body()));
+    };
+    let Some(db) = req.app_data::<web::Data<Db>>().cloned() else {
+        error!("auth middleware has no database");
+        return Ok(req.into_response(
+            HttpResponse::InternalServerError()
+                .finish()
+                .map_into_right_body(),
+        ));
+    };
+
+    let id = payment_id.clone();
+    let caller = spawn_blocking(move || db.authenticate(&hash_token(&token), id.as_deref()))
+        .await
+        .unwrap();
+    let response = match caller {
+        Ok(Some(caller)) if caller.allows(scope, payment_id.as_deref()) => {
+            if let Caller::Key { id, .. } = &caller {
+                debug!(api_key_id = %id, %scope, "authenticated");
+            }
+            req.extensions_mut().insert(caller);
+            return next
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body);
+        }
+        Ok(Some(caller)) => {
+            debug!(?caller, %scope, "caller lacks scope");
+            HttpResponse::Forbidden().finish()
+        }
+        Ok(None) => HttpResponse::Unauthorized().finish(),
+        Err(e) => {
+            error!(error = %e, "api key lookup failed");
+            HttpResponse::InternalServerError().finish()
+        }
+    };
+    Ok(req.into_response(response.map_into_right_body()))
+}
@@ -0,0 +1,213 @@
+use crate::{
+    auth::{generate_api_key, hash_token, Scope},
+    db::{ApiKey, Db, Merchant, DEFAULT_MERCHANT},
+    migrations::{self, latest},
+    psbt,
+    utils::is_segwit_v0_address,
+    wallet::payout_address,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs;
+use uuid::Uuid;
+
+const USAGE: &str = "usage:
+  litegate                              run the server
+  litegate psbt list                    list sweeps waiting for a signature
+  litegate psbt export <id> [file]      write an unsigned PSBT (base64) to file or stdout
+  litegate psbt submit <id> <file>      broadcast a signed PSBT (base64) read from file
+  litegate psbt cancel <id>             discard an unsigned PSBT; its payments are swept anew
+  litegate migrate                      apply pending database migrations
+  litegate migrate status               show the schema version and pending migrations
+  litegate keys list                    list API keys
+  litegate keys create <name> <scopes> [merchant]
+                                        create an API key of a merchant (default: default);
+                                        scopes comma-separated from payments:create,
+                                        payments:read, webhooks, admin
+  litegate keys revoke <id>             revoke an API key
+  litegate merchants list               list merchants
+  litegate merchants create <name> [setting=value ...]
+  litegate merchants update <id> [setting=value ...]
+                                        settings: payout_address, payout_xpub, webhook_url,
+                                        webhook_secret, confirmations, default_ttl;
+                                        an empty value clears one";
+
+/// Runs a one-shot command instead of the server.
+pub async fn run(args: &[String], db: &Db) -> Result<()> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["psbt", "list"] => {
+            for p in db.unsigned_psbts()? {
+                println!(
+                    "{}\tpayments={}\tcreated_at={}",
+                    p.id,
+                    p.payment_ids.join(","),
+                    p.created_at
+                );
+            }
+            Ok(())
+        }
+        ["psbt", "export", id, rest @ ..] => {
+            let record = db
+                .find_psbt(id)?
+                .ok_or_else(|| anyhow!("psbt {id} not found"))?;
+            match rest {
+                [] => println!("{}", record.psbt),
+                [file] => fs::write(file, &record.psbt).with_context(|| format!("write {file}"))?,
+                _ => bail!(USAGE),
+            }
+            Ok(())
+        }
+        ["psbt", "submit", id, file] => {
+            let signed = fs::read_to_string(file).with_context(|| format!("read {file}"))?;
+            let txid = psbt::submit(db, id, &signed).await?;
+            println!("{txid}");
+            Ok(())
+        }
+        ["psbt", "cancel", id] => psbt::cancel(db, id),
+        ["migrate"] => {
+            let applied = migrations::migrate(&mut db.0.lock().unwrap())?;
+            for m in &applied {
+                println!("applied {}\t{}", m.version, m.name);
+            }
+            println!("schema at version {}", latest());
+            Ok(())
+        }
+        ["migrate", "status"] => {
+            let conn = db.0.lock().unwrap();
+            println!(
+                "schema at version {}, binary expects {}",
+                migrations::current_version(&conn)?,
+                latest()
+            );
+            for m in migrations::pending(&conn)? {
+                println!("pending {}\t{}", m.version, m.name);
+            }
+            Ok(())
+        }
+        ["keys", "list"] => {
+            for k in db.api_keys()? {
+                let scopes: Vec<&str> = k.scopes.iter().map(|s| s.as_str()).collect();
+                let state = match k.revoked_at {
+                    Some(at) => format!("revoked_at={at}"),
+                    None => "active".into(),
+                };
+                println!("{}\t{}\t{}\t{state}", k.id, k.name, scopes.join(","));
+            }
+            Ok(())
+        }
+        ["keys", "create", name, scopes, rest @ ..] => {
+            let merchant_id = match rest {
+                [] => DEFAULT_MERCHANT,
+                [m] => m,
+                _ => bail!(USAGE),
+            };
+            if db.find_merchant(merchant_id)?.is_none() {
+                bail!("no merchant {merchant_id}");
+            }
+            let scopes = scopes
+                .split(',')
+                .map(|s| s.trim().parse::<Scope>().map_err(|e| anyhow!(e)))
+                .collect::<Result<Vec<_>>>()?;
+            let key = generate_api_key();
+            let record = ApiKey {
+                id: Uuid::new_v4().to_string(),
+                name: name.to_string(),
+                key_hash: hash_token(&key),
+                scopes,
+                merchant_id: merchant_id.to_string(),
+                created_at: 0,
+                revoked_at: None,
+            };
+            db.insert_api_key(&record)?;
+            eprintln!("created key {} (shown once, store it now)", record.id);
+            println!("{key}");
+            Ok(())
+        }
+        ["keys", "revoke", id] => {
+            if !db.revoke_api_key(id)? {
+                bail!("no active key {id}");
+            }
+            Ok(())
+        }
+        ["merchants", "list"] => {
+            for m in db.merchants()? {
+                let payout = match (&m.payout_address, &m.payout_xpub) {
+                    (Some(a), _) => a.clone(),
+                    (None, Some(_)) => format!("xpub@{}", m.payout_index),
+                    (None, None) => "-".into(),
+                };
+                println!(
+                    "{}\t{}\tpayout={payout}\twebhook={}",
+                    m.id,
+                    m.name,
+                    m.webhook_url.as_deref().unwrap_or("-")
+                );
+            }
+            Ok(())
+        }
+        ["merchants", "create", name, settings @ ..] => {
+            let mut m = Merchant {
+                id: Uuid::new_v4().to_string(),
+                name: name.to_string(),
+                ..Default::default()
+            };
+            apply_settings(&mut m, settings)?;
+            if m.payout_address.is_none() && m.payout_xpub.is_none() {
+                bail!("a merchant needs payout_address or payout_xpub");
+            }
+            db.insert_merchant(&m)?;
+            println!("{}", m.id);
+            Ok(())
+        }
+        ["merchants", "update", id, settings @ ..] => {
+            let mut m = db
+                .find_merchant(id)?
+                .ok_or_else(|| anyhow!("no merchant {id}"))?;
+            apply_settings(&mut m, settings)?;
+            if !m.is_default() && m.payout_address.is_none() && m.payout_xpub.is_none() {
+                bail!("a merchant needs payout_address or payout_xpub");
+            }
+            db.update_merchant(&m)?;
+            Ok(())
+        }
+        _ => bail!(USAGE),
+    }
+}
+
+/// Applies `setting=value` arguments of `merchants create|update`.
+fn apply_settings(m: &mut Merchant, settings: &[&str]) -> Result<()> {
+    for s in settings {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected setting=value, got {s:?}"))?;
+        let value = Some(value.trim().to_string()).filter(|v| !v.is_empty());
+        let number = |v: &Option<String>| {
+            v.as_deref()
+                .map(|n| {
+                    n.parse::<u64>()
+                        .with_context(|| format!("{key} must be a number"))
+                })
+                .transpose()
+        };
+        match key {
+            "payout_address" => {
+                if let Some(a) = value.as_deref().filter(|a| !is_segwit_v0_address(a)) {
+                    bail!("payout_address {a:?} is not a segwit (ltc1…) address");
+                }
+                m.payout_address = value;
+            }
+            "payout_xpub" => {
+                if let Some(x) = &value {
+                    payout_address(x, 0)?;
+                }
+                m.payout_xpub = value;
+            }
+            "webhook_url" => m.webhook_url = value,
+            "webhook_secret" => m.webhook_secret = value,
+            "confirmations" => m.confirmations = number(&value)?,
+            "default_ttl" => m.default_ttl = number(&value)?,
+            _ => bail!("unknown setting {key:?}"),
+        }
+    }
+    Ok(())
+}
@@ -1,118 +1,2089 @@
-use rusqlite::{params, Connection, OptionalExtension, Result as SqliteResult};
+use crate::{
+    auth::{Caller, Scope},
+    migrations,
+    utils::format_ltc,
+    webhook::{self, WebhookEvent},
+};
+use rusqlite::{
+    params, params_from_iter,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Type, Value, ValueRef},
+    Connection, OptionalExtension, Result as SqliteResult, Row, ToSql,
+};
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, Mutex};
-use tracing::instrument;
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{instrument, warn};
+use uuid::Uuid;
 
 #[derive(Clone)]
 pub struct Db(pub Arc<Mutex<Connection>>);
 
+/// Customer-facing lifecycle of a payment. Custody of the funds is tracked
+/// separately, on the `sweeps` table; only a confirmed sweep of a settled
+/// payment moves it to `Swept`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentStatus {
+    /// Address issued, nothing received yet
+    Created,
+    /// Funds seen in the mempool
+    SeenInMempool,
+    /// Funds confirmed, fewer than `CONFIRMATIONS` deep
+    Confirming,
+    /// Confirmed balance equals the requested amount, or is off by no more
+    /// than the accepted tolerance
+    Paid,
+    /// Confirmed balance below the requested amount; a top-up may still settle it
+    Underpaid,
+    /// Confirmed balance above the requested amount
+    Overpaid,
+    /// TTL passed before the payment settled
+    Expired,
+    /// Funds confirmed on an expired payment after its deadline, or an
+    /// underpayment it expired with; see [`LateAction`] for what happens to
+    /// them
+    PaidLate,
+    /// Funds of a settled payment moved to `MAIN_ADDRESS` and confirmed
+    Swept,
+}
+
+impl PaymentStatus {
+    pub const ALL: [Self; 9] = [
+        Self::Created,
+        Self::SeenInMempool,
+        Self::Confirming,
+        Self::Paid,
+        Self::Underpaid,
+        Self::Overpaid,
+        Self::Expired,
+        Self::PaidLate,
+        Self::Swept,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::SeenInMempool => "seen_in_mempool",
+            Self::Confirming => "confirming",
+            Self::Paid => "paid",
+            Self::Underpaid => "underpaid",
+            Self::Overpaid => "overpaid",
+            Self::Expired => "expired",
+            Self::PaidLate => "paid_late",
+            Self::Swept => "swept",
+        }
+    }
+
+    /// States a payment may move to from `self`.
+    pub fn next(self) -> &'static [Self] {
+        use PaymentStatus::*;
+        match self {
+            Created => &[
+                SeenInMempool,
+                Confirming,
+                Paid,
+                Underpaid,
+                Overpaid,
+                Expired,
+            ],
+            // detected funds hold off expiry until they settle, or back to
+            // `Created` if they vanish
+            SeenInMempool => &[Created, Confirming, Paid, Underpaid, Overpaid],
+            Confirming => &[Created, Paid, Underpaid, Overpaid],
+            Underpaid => &[Confirming, Paid, Overpaid, Expired],
+            Paid | Overpaid | PaidLate => &[Swept],
+            // funds that confirmed by the deadline still settle it
+            Expired => &[Paid, Overpaid, PaidLate],
+            Swept => &[],
+        }
+    }
+
+    pub fn can_become(self, to: Self) -> bool {
+        self.next().contains(&to)
+    }
+
+    /// Still waiting for (more) funds with none on their way, so it may
+    /// expire.
+    pub fn is_open(self) -> bool {
+        self.can_become(Self::Expired)
+    }
+
+    /// SQL list of the states that may move to `to`, for guarded UPDATEs.
+    fn sources_sql(to: Self) -> String {
+        let sources: Vec<Self> = Self::ALL.into_iter().filter(|s| s.can_become(to)).collect();
+        Self::list_sql(&sources)
+    }
+
+    /// `states` as an SQL list.
+    fn list_sql(states: &[Self]) -> String {
+        let quoted: Vec<String> = states.iter().map(|s| format!("'{}'", s.as_str())).collect();
+        format!("({})", quoted.join(","))
+    }
+}
+
+impl fmt::Display for PaymentStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for PaymentStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|st| st.as_str() == s)
+            .ok_or_else(|| format!("unknown payment status {s:?}"))
+    }
+}
+
+impl ToSql for PaymentStatus {
+    fn to_sql(&self) -> SqliteResult<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for PaymentStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: String| FromSqlError::Other(e.into()))
+    }
+}
+
+/// What happens to the funds of a `paid_late` payment.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LateAction {
+    /// Swept to the merchant's payout like a settled payment
+    Accept,
+    /// Held on the deposit address until someone decides
+    Review,
+    /// Held until a refund address is set, then swept there
+    Refund,
+}
+
+impl LateAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Accept => "accept",
+            Self::Review => "review",
+            Self::Refund => "refund",
+        }
+    }
+}
+
+impl fmt::Display for LateAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for LateAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Self::Accept, Self::Review, Self::Refund]
+            .into_iter()
+            .find(|a| a.as_str() == s)
+            .ok_or_else(|| format!("unknown late payment action {s:?}"))
+    }
+}
+
+impl ToSql for LateAction {
+    fn to_sql(&self) -> SqliteResult<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for LateAction {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: String| FromSqlError::Other(e.into()))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Payment {
     pub id: String,
     pub address: String,
+    /// AES-encrypted key of legacy random-key payments; empty for HD payments
     pub wif_enc: String,
-    pub amount: f64,
-    pub status: String,
+    /// BIP84 receive index the address was derived from; `None` for legacy rows
+    pub derivation_index: Option<u32>,
+    /// txid of the sweep that moved this payment's funds to `MAIN_ADDRESS`
+    pub sweep_txid: Option<String>,
+    /// requested amount in litoshis
+    pub amount: u64,
+    pub status: PaymentStatus,
     pub created_at: i64,
     pub updated_at: i64,
     pub expires_at: i64,
+    /// merchant's own reference, e.g. their order number
+    pub order_id: Option<String>,
+    pub description: Option<String>,
+    /// arbitrary merchant data, echoed back untouched
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+    pub merchant_id: String,
+    /// what the last sync saw on chain
+    pub chain: ChainState,
+    /// confirmed litoshis minus `amount` when the payment settled; negative
+    /// for an underpayment
+    pub amount_delta: Option<i64>,
+    /// set once the payment is `paid_late`
+    pub late_action: Option<LateAction>,
+    /// where the funds of a `refund` go
+    pub refund_address: Option<String>,
+}
+
+impl Payment {
+    /// Past its deadline while still open. Underpaid payments get
+    /// `underpaid_grace` more seconds to be topped up, and do not expire
+    /// while a top-up is unconfirmed.
+    pub fn is_overdue(&self, now: i64, underpaid_grace: i64) -> bool {
+        let grace = if self.status == PaymentStatus::Underpaid {
+            underpaid_grace
+        } else {
+            0
+        };
+        self.status.is_open()
+            && self.chain.received <= self.chain.confirmed
+            && self.expires_at != 0
+            && self.expires_at + grace < now
+    }
+
+    /// Refund address of late funds that are to be sent back.
+    pub fn refund_to(&self) -> Option<&str> {
+        match self.late_action {
+            Some(LateAction::Refund) => self.refund_address.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// A payment's deposit address as of its last sync with Electrum.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChainState {
+    /// litoshis, confirmed plus unconfirmed
+    pub received: u64,
+    pub confirmed: u64,
+    /// first transaction paying the address; kept once set
+    pub first_seen_txid: Option<String>,
+    pub first_seen_at: Option<i64>,
+    /// block of the newest confirmed funds, which confirmations count from
+    pub confirmed_height: Option<u64>,
+    pub last_synced_at: Option<i64>,
+}
+
+/// One output paying a payment's address.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PaymentTx {
+    pub txid: String,
+    pub vout: u32,
+    /// litoshis
+    pub value: u64,
+    /// `None` while in the mempool
+    pub height: Option<u64>,
+    pub first_seen_at: i64,
+    /// when a sync first saw it mined
+    pub confirmed_at: Option<i64>,
+    /// timestamp of the block that mined it
+    pub block_time: Option<i64>,
+    /// the transaction vanished from the address history: replaced, evicted
+    /// from the mempool or reorged out
+    pub double_spent: bool,
+}
+
+impl PaymentTx {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "txid": self.txid,
+            "vout": self.vout,
+            "value": format_ltc(self.value),
+            "value_litoshis": self.value,
+            "height": self.height,
+            "first_seen_at": self.first_seen_at,
+            "confirmed_at": self.confirmed_at,
+            "block_time": self.block_time,
+            "double_spent": self.double_spent,
+        })
+    }
+}
+
+const PAYMENT_TX_COLUMNS: &str =
+    "txid,vout,value,height,first_seen_at,confirmed_at,double_spent,block_time";
+
+fn payment_tx_from_row(r: &Row) -> SqliteResult<PaymentTx> {
+    Ok(PaymentTx {
+        txid: r.get(0)?,
+        vout: r.get(1)?,
+        value: r.get(2)?,
+        height: r.get(3)?,
+        first_seen_at: r.get(4)?,
+        confirmed_at: r.get(5)?,
+        double_spent: r.get(6)?,
+        block_time: r.get(7)?,
+    })
+}
+
+fn payment_txs_of(c: &Connection, payment_id: &str) -> SqliteResult<Vec<PaymentTx>> {
+    let mut stmt = c.prepare(&format!(
+        "SELECT {PAYMENT_TX_COLUMNS} FROM payment_txs WHERE payment_id=?
+         ORDER BY first_seen_at, txid, vout"
+    ))?;
+    let rows = stmt
+        .query_map([payment_id], payment_tx_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(rows)
+}
+
+impl ChainState {
+    /// Confirmations of the newest confirmed funds at chain tip `tip`.
+    pub fn confirmations(&self, tip: u64) -> u64 {
+        self.confirmed_height
+            .map_or(0, |h| (tip + 1).saturating_sub(h))
+    }
+}
+
+/// Filters for [`Db::list_payments`]; empty / `None` fields match everything.
+/// Time bounds are exclusive, amount bounds (litoshis) inclusive.
+#[derive(Debug, Default, Clone)]
+pub struct PaymentFilter {
+    pub statuses: Vec<PaymentStatus>,
+    pub created_after: Option<i64>,
+    pub created_before: Option<i64>,
+    pub updated_after: Option<i64>,
+    pub updated_before: Option<i64>,
+    pub min_amount: Option<u64>,
+    pub max_amount: Option<u64>,
+    pub address: Option<String>,
+    pub order_id: Option<String>,
+    /// case-insensitive substring of `order_id` or `description`
+    pub search: Option<String>,
+    /// top-level metadata key and the value it must equal (compared as text)
+    pub metadata: Option<(String, String)>,
+    pub merchant_id: Option<String>,
+}
+
+/// Column a payment listing is ordered by; ties are broken by id.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PaymentSort {
+    #[default]
+    CreatedAt,
+    UpdatedAt,
+    Amount,
+}
+
+impl PaymentSort {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::CreatedAt => "created_at",
+            Self::UpdatedAt => "updated_at",
+            Self::Amount => "amount",
+        }
+    }
+
+    fn column(self) -> &'static str {
+        match self {
+            Self::CreatedAt => "created_at",
+            Self::UpdatedAt => "updated_at",
+            Self::Amount => "amount_litoshis",
+        }
+    }
+
+    /// Keyset position of `p` in this order, for [`PaymentPage::after`].
+    pub fn key(self, p: &Payment) -> (i64, String) {
+        let value = match self {
+            Self::CreatedAt => p.created_at,
+            Self::UpdatedAt => p.updated_at,
+            Self::Amount => p.amount as i64,
+        };
+        (value, p.id.clone())
+    }
+}
+
+impl FromStr for PaymentSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Self::CreatedAt, Self::UpdatedAt, Self::Amount]
+            .into_iter()
+            .find(|st| st.as_str() == s)
+            .ok_or_else(|| format!("unknown sort {s:?}"))
+    }
+}
+
+/// One page of a keyset-paginated payment listing.
+#[derive(Debug, Clone)]
+pub struct PaymentPage {
+    pub sort: PaymentSort,
+    pub descending: bool,
+    /// key of the last row of the previous page, see [`PaymentSort::key`]
+    pub after: Option<(i64, String)>,
+    pub limit: usize,
+}
+
+/// Outcome of [`Db::claim_idempotency_key`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdempotencyClaim {
+    /// The caller is to create the payment with this id, then complete or
+    /// release the key. A claim whose request died unfinished is handed out
+    /// again with its original id, so that payment may already exist.
+    New(String),
+    /// Same key and request completed before; the id of its payment and
+    /// its read token as sealed by [`crate::utils::encrypt_secret`], if kept
+    Replay(String, Option<String>),
+    /// Same key, first request not finished yet
+    InProgress,
+    /// Same key, different request
+    Mismatch,
+}
+
+/// Merchant everything created before multi-tenancy belongs to.
+pub const DEFAULT_MERCHANT: &str = "default";
+
+/// A tenant with its own API keys, payments, payout destination and webhook
+/// receiver. Unset settings of the `default` merchant fall back to the
+/// process-wide configuration (`MAIN_ADDRESS`, `WEBHOOK_URL`, ...).
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Merchant {
+    pub id: String,
+    pub name: String,
+    /// fixed address sweeps pay to
+    pub payout_address: Option<String>,
+    /// account xpub sweeps pay to, a fresh receive address each time
+    pub payout_xpub: Option<String>,
+    /// next receive index of `payout_xpub`
+    pub payout_index: u32,
+    pub webhook_url: Option<String>,
+    /// comma-separated like `WEBHOOK_SECRET`
+    #[serde(skip_serializing)]
+    pub webhook_secret: Option<String>,
+    /// overrides `CONFIRMATIONS`
+    pub confirmations: Option<u64>,
+    /// TTL of payments created without one, seconds
+    pub default_ttl: Option<u64>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl Merchant {
+    pub fn is_default(&self) -> bool {
+        self.id == DEFAULT_MERCHANT
+    }
+}
+
+const MERCHANT_COLUMNS: &str = "id,name,payout_address,payout_xpub,payout_index,webhook_url,\
+     webhook_secret,confirmations,default_ttl,created_at,updated_at";
+
+fn merchant_from_row(r: &Row) -> SqliteResult<Merchant> {
+    Ok(Merchant {
+        id: r.get(0)?,
+        name: r.get(1)?,
+        payout_address: r.get(2)?,
+        payout_xpub: r.get(3)?,
+        payout_index: r.get(4)?,
+        webhook_url: r.get(5)?,
+        webhook_secret: r.get(6)?,
+        confirmations: r.get(7)?,
+        default_ttl: r.get(8)?,
+        created_at: r.get(9)?,
+        updated_at: r.get(10)?,
+    })
+}
+
+/// A stored API key. The key itself is shown once at creation; only its
+/// SHA-256 is kept.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub key_hash: String,
+    pub scopes: Vec<Scope>,
+    pub merchant_id: String,
+    pub created_at: i64,
+    pub revoked_at: Option<i64>,
+}
+
+const API_KEY_COLUMNS: &str = "id,name,key_hash,scopes,merchant_id,created_at,revoked_at";
+
+fn api_key_from_row(r: &Row) -> SqliteResult<ApiKey> {
+    Ok(ApiKey {
+        id: r.get(0)?,
+        name: r.get(1)?,
+        key_hash: r.get(2)?,
+        scopes: json_column(r, 3)?,
+        merchant_id: r.get(4)?,
+        created_at: r.get(5)?,
+        revoked_at: r.get(6)?,
+    })
+}
+
+/// An unsigned sweep exported for offline signing in watch-only mode.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PsbtRecord {
+    pub id: String,
+    /// payments the sweep covers
+    pub payment_ids: Vec<String>,
+    /// base64 PSBT as exported, without signatures
+    pub psbt: String,
+    /// unsigned / broadcast / cancelled / stale
+    pub status: String,
+    pub txid: Option<String>,
+    /// sweep this PSBT replaces via RBF, for a higher fee; `None` for a new sweep
+    pub replaces_sweep: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// One input spent by a sweep transaction.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SweepInput {
+    pub txid: String,
+    pub vout: u32,
+    pub value: u64,
+    pub payment_id: String,
+    pub address: String,
+}
+
+/// A sweep transaction that was replaced by a higher-fee version.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FeeBump {
+    pub txid: String,
+    pub fee: u64,
+    pub fee_rate: u64,
+    /// tip height when it was replaced
+    pub height: u64,
+    pub replaced_at: i64,
+}
+
+/// A broadcast sweep transaction, tracked until it confirms.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sweep {
+    pub id: String,
+    pub txid: String,
+    pub raw_hex: String,
+    /// litoshis
+    pub fee: u64,
+    /// litoshis per vbyte
+    pub fee_rate: u64,
+    pub inputs: Vec<SweepInput>,
+    pub payment_ids: Vec<String>,
+    /// broadcast / confirmed / failed
+    pub status: String,
+    /// tip height when the current txid was broadcast
+    pub broadcast_height: u64,
+    /// earlier txids this sweep replaced via RBF, oldest first
+    pub fee_bumps: Vec<FeeBump>,
+    pub broadcast_attempts: u32,
+    pub last_broadcast_at: i64,
+    pub confirmed_height: Option<u64>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+const SWEEP_COLUMNS: &str = "id,txid,raw_hex,fee,fee_rate,inputs,payment_ids,status,\
+     broadcast_height,fee_bumps,broadcast_attempts,last_broadcast_at,confirmed_height,\
+     created_at,updated_at";
+
+fn sweep_from_row(r: &Row) -> SqliteResult<Sweep> {
+    Ok(Sweep {
+        id: r.get(0)?,
+        txid: r.get(1)?,
+        raw_hex: r.get(2)?,
+        fee: r.get(3)?,
+        fee_rate: r.get(4)?,
+        inputs: json_column(r, 5)?,
+        payment_ids: json_column(r, 6)?,
+        status: r.get(7)?,
+        broadcast_height: r.get(8)?,
+        fee_bumps: json_column(r, 9)?,
+        broadcast_attempts: r.get(10)?,
+        last_broadcast_at: r.get(11)?,
+        confirmed_height: r.get(12)?,
+        created_at: r.get(13)?,
+        updated_at: r.get(14)?,
+    })
+}
+
+/// A webhook event in the outbox. It is written in the same transaction as
+/// the change it announces and delivered by `webhook::start`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutboxEvent {
+    pub id: String,
+    pub event: String,
+    pub payment_id: String,
+    pub merchant_id: String,
+    /// exact request body, built when the event was recorded
+    pub payload: String,
+    /// pending / delivered / dead
+    pub status: String,
+    pub attempts: u32,
+    pub next_attempt_at: i64,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub delivered_at: Option<i64>,
+}
+
+const OUTBOX_COLUMNS: &str = "id,event,payment_id,payload,status,attempts,next_attempt_at,\
+     last_error,created_at,updated_at,delivered_at,merchant_id";
+
+fn outbox_from_row(r: &Row) -> SqliteResult<OutboxEvent> {
+    Ok(OutboxEvent {
+        id: r.get(0)?,
+        event: r.get(1)?,
+        payment_id: r.get(2)?,
+        payload: r.get(3)?,
+        status: r.get(4)?,
+        attempts: r.get(5)?,
+        next_attempt_at: r.get(6)?,
+        last_error: r.get(7)?,
+        created_at: r.get(8)?,
+        updated_at: r.get(9)?,
+        delivered_at: r.get(10)?,
+        merchant_id: r.get(11)?,
+    })
+}
+
+/// Which outbox events `GET /webhooks/deliveries` returns; `None`/empty
+/// fields do not filter.
+#[derive(Debug, Clone, Default)]
+pub struct WebhookEventFilter {
+    pub merchant_id: Option<String>,
+    pub payment_id: Option<String>,
+    /// pending / delivered / dead
+    pub statuses: Vec<String>,
+    /// exclusive bounds on `created_at`
+    pub created_after: Option<i64>,
+    pub created_before: Option<i64>,
+}
+
+/// One POST of an outbox event to the receiver.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebhookAttempt {
+    pub event_id: String,
+    pub attempted_at: i64,
+    /// `None` when no response arrived (connect error, timeout)
+    pub status_code: Option<u16>,
+    pub latency_ms: u64,
+    /// start of the response body
+    pub response: Option<String>,
+    pub error: Option<String>,
+}
+
+/// What to do with an event after an attempt, see [`Db::record_webhook_attempt`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryOutcome {
+    Delivered,
+    /// failed, try again at this Unix time
+    Retry(i64),
+    /// failed for the last time
+    Dead,
+}
+
+/// Queues `event` for `payment_id` with the payment as stored right now;
+/// call it inside the transaction that made the change.
+fn enqueue_event(c: &Connection, event: WebhookEvent, payment_id: &str) -> SqliteResult<()> {
+    let payment = c.query_row(
+        &format!("SELECT {PAYMENT_COLUMNS} FROM payments WHERE id=?"),
+        [payment_id],
+        payment_from_row,
+    )?;
+    let txs = payment_txs_of(c, payment_id)?;
+    let id = Uuid::new_v4().to_string();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    c.execute(
+        "INSERT INTO webhook_events(id,event,payment_id,merchant_id,payload,status,attempts,
+                                    next_attempt_at,created_at,updated_at)
+         VALUES(?,?,?,?,?,'pending',0,?,?,?)",
+        params![
+            id,
+            event.as_str(),
+            payment_id,
+            payment.merchant_id,
+            webhook::payload(&id, event, now, &payment, &txs).to_string(),
+            now,
+            now,
+            now
+        ],
+    )?;
+    Ok(())
+}
+
+const PSBT_COLUMNS: &str = "id,payment_ids,psbt,status,txid,created_at,updated_at,replaces_sweep";
+
+fn json_column<T: serde::de::DeserializeOwned>(r: &Row, idx: usize) -> SqliteResult<T> {
+    let raw: String = r.get(idx)?;
+    serde_json::from_str(&raw)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(e)))
+}
+
+fn psbt_from_row(r: &Row) -> SqliteResult<PsbtRecord> {
+    Ok(PsbtRecord {
+        id: r.get(0)?,
+        payment_ids: json_column(r, 1)?,
+        psbt: r.get(2)?,
+        status: r.get(3)?,
+        txid: r.get(4)?,
+        created_at: r.get(5)?,
+        updated_at: r.get(6)?,
+        replaces_sweep: r.get(7)?,
+    })
+}
+
+const PAYMENT_COLUMNS: &str = "id,address,wif_enc,derivation_index,sweep_txid,amount_litoshis,\
+     status,created_at,updated_at,expires_at,order_id,description,metadata,merchant_id,\
+     received_litoshis,confirmed_litoshis,first_seen_txid,first_seen_at,confirmed_height,last_synced_at,\
+     amount_delta,late_action,refund_address";
+
+fn payment_from_row(r: &Row) -> SqliteResult<Payment> {
+    Ok(Payment {
+        id: r.get(0)?,
+        address: r.get(1)?,
+        wif_enc: r.get(2)?,
+        derivation_index: r.get(3)?,
+        sweep_txid: r.get(4)?,
+        amount: r.get(5)?,
+        status: r.get(6)?,
+        created_at: r.get(7)?,
+        updated_at: r.get(8)?,
+        expires_at: r.get(9)?,
+        order_id: r.get(10)?,
+        description: r.get(11)?,
+        metadata: json_column(r, 12)?,
+        merchant_id: r.get(13)?,
+        chain: ChainState {
+            received: r.get(14)?,
+            confirmed: r.get(15)?,
+            first_seen_txid: r.get(16)?,
+            first_seen_at: r.get(17)?,
+            confirmed_height: r.get(18)?,
+            last_synced_at: r.get(19)?,
+        },
+        amount_delta: r.get(20)?,
+        late_action: r.get(21)?,
+        refund_address: r.get(22)?,
+    })
 }
 
 impl Db {
+    /// Opens the database, refusing one whose schema is newer than this
+    /// binary. Pending migrations are applied separately, see
+    /// [`migrations::on_startup`].
     #[instrument(skip(path))]
-    pub fn open(path: &str) -> SqliteResult<Self> {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
         let conn = Connection::open(path)?;
-        conn.execute_batch(
-            "CREATE TABLE IF NOT EXISTS payments(
-                id TEXT PRIMARY KEY,
-                address TEXT UNIQUE,
-                wif_enc TEXT NOT NULL,
-                amount REAL,
-                status TEXT,
-                created_at INTEGER,
-                updated_at INTEGER,
-                expires_at INTEGER
-            )",
-        )?;
-        conn.execute_batch(
-            "CREATE INDEX IF NOT EXISTS idx_payments_expires_at ON payments(expires_at)",
-        )?;
+        migrations::check_compatible(&conn)?;
         Ok(Self(Arc::new(Mutex::new(conn))))
     }
 
-    pub fn insert(&self, p: &Payment) -> SqliteResult<()> {
+    /// Reserves the next BIP84 receive index. Indices are never reused, so a
+    /// failed insert leaves a gap rather than a duplicate address.
+    pub fn next_derivation_index(&self) -> SqliteResult<u32> {
+        self.0.lock().unwrap().query_row(
+            "INSERT INTO counters(name,value) VALUES('derivation_index',1)
+             ON CONFLICT(name) DO UPDATE SET value=value+1
+             RETURNING value-1",
+            [],
+            |r| r.get(0),
+        )
+    }
+
+    /// Records what a sync saw at the payment's address. The first-seen txid
+    /// and time are only set once; `updated_at` is left alone, it tracks the
+    /// payment rather than the chain.
+    pub fn save_chain_state(&self, id: &str, state: &ChainState) -> SqliteResult<()> {
+        self.0.lock().unwrap().execute(
+            "UPDATE payments
+             SET received_litoshis=?1,
+                 confirmed_litoshis=?2,
+                 first_seen_at=COALESCE(first_seen_at,
+                     CASE WHEN ?3 IS NULL THEN NULL ELSE strftime('%s','now') END),
+                 first_seen_txid=COALESCE(first_seen_txid, ?3),
+                 confirmed_height=?4,
+                 last_synced_at=strftime('%s','now')
+             WHERE id=?5",
+            params![
+                state.received,
+                state.confirmed,
+                state.first_seen_txid,
+                state.confirmed_height,
+                id
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Outputs paying payment `payment_id`, oldest first.
+    pub fn payment_txs(&self, payment_id: &str) -> SqliteResult<Vec<PaymentTx>> {
+        payment_txs_of(&self.0.lock().unwrap(), payment_id)
+    }
+
+    /// Records new outputs `(txid, vout, value)` paying payment `payment_id`
+    /// and refreshes every known one against the address history `(txid,
+    /// height, block time)`: heights and confirmation times follow it, and a transaction
+    /// missing from it is flagged double-spent until it shows up again. An
+    /// empty history flags nothing: a server that lost or has not yet indexed
+    /// the address looks the same as one where every transaction vanished.
+    pub fn record_payment_txs(
+        &self,
+        payment_id: &str,
+        outputs: &[(String, u32, u64)],
+        history: &[(String, i64, Option<i64>)],
+    ) -> SqliteResult<()> {
+        let mut c = self.0.lock().unwrap();
+        let tx = c.transaction()?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO payment_txs(payment_id,txid,vout,value,first_seen_at)
+                 VALUES(?,?,?,?,strftime('%s','now'))
+                 ON CONFLICT(payment_id,txid,vout) DO NOTHING",
+            )?;
+            for (txid, vout, value) in outputs {
+                insert.execute(params![payment_id, txid, vout, value])?;
+            }
+            let mut update = tx.prepare(
+                "UPDATE payment_txs
+                 SET height=?1,
+                     confirmed_at=CASE WHEN ?1 IS NULL THEN NULL
+                                       ELSE COALESCE(confirmed_at, strftime('%s','now')) END,
+                     block_time=CASE WHEN ?1 IS NULL THEN NULL
+                                     ELSE COALESCE(?4, CASE WHEN height=?1 THEN block_time END) END,
+                     double_spent=0
+                 WHERE payment_id=?2 AND txid=?3",
+            )?;
+            for (txid, height, block_time) in history {
+                let height = (*height > 0).then_some(*height);
+                update.execute(params![height, payment_id, txid, block_time])?;
+            }
+        }
+        if !history.is_empty() {
+            let seen: Vec<&str> = history.iter().map(|(txid, ..)| txid.as_str()).collect();
+            tx.execute(
+                "UPDATE payment_txs
+                 SET double_spent=1, height=NULL, confirmed_at=NULL, block_time=NULL
+                 WHERE payment_id=? AND txid NOT IN (SELECT value FROM json_each(?))",
+                params![payment_id, serde_json::to_string(&seen).unwrap()],
+            )?;
+        }
+        tx.commit()
+    }
+
+    /// Decides on the funds of a `paid_late` payment not yet being swept;
+    /// `false` if there is no such payment.
+    pub fn resolve_late(
+        &self,
+        id: &str,
+        action: LateAction,
+        refund_address: Option<&str>,
+    ) -> SqliteResult<bool> {
+        let n = self.0.lock().unwrap().execute(
+            "UPDATE payments
+             SET late_action=?,
+                 refund_address=?,
+                 updated_at=strftime('%s','now')
+             WHERE id=? AND status='paid_late' AND sweep_txid IS NULL",
+            params![action, refund_address, id],
+        )?;
+        Ok(n == 1)
+    }
+
+    /// Marks payments whose address is known to be unchanged as synced now.
+    pub fn touch_synced(&self, ids: &[String]) -> SqliteResult<()> {
+        let mut c = self.0.lock().unwrap();
+        let tx = c.transaction()?;
+        {
+            let mut stmt =
+                tx.prepare("UPDATE payments SET last_synced_at=strftime('%s','now') WHERE id=?")?;
+            for id in ids {
+                stmt.execute([id])?;
+            }
+        }
+        tx.commit()
+    }
+
+    pub fn set_chain_tip(&self, height: u64) -> SqliteResult<()> {
         self.0.lock().unwrap().execute(
-            "INSERT INTO payments(id,address,wif_enc,amount,status,created_at,updated_at,expires_at)
-             VALUES(?,?,?,?,?,strftime('%s','now'),strftime('%s','now'),?)",
-            params![p.id, p.address, p.wif_enc, p.amount, "pending", p.expires_at],
+            "INSERT INTO counters(name,value) VALUES('chain_tip',?1)
+             ON CONFLICT(name) DO UPDATE SET value=?1",
+            [height],
         )?;
         Ok(())
     }
 
+    /// Height of the best block the watcher has seen; `None` before its first
+    /// connection.
+    pub fn chain_tip(&self) -> SqliteResult<Option<u64>> {
+        self.0
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT value FROM counters WHERE name='chain_tip'",
+                [],
+                |r| r.get(0),
+            )
+            .optional()
+    }
+
+    /// Stores a new payment together with the hash of its checkout read token.
+    pub fn insert(&self, p: &Payment, read_token_hash: &str) -> SqliteResult<()> {
+        let mut c = self.0.lock().unwrap();
+        let tx = c.transaction()?;
+        tx.execute(
+            "INSERT INTO payments(id,address,wif_enc,derivation_index,amount_litoshis,status,read_token_hash,
+                                  order_id,description,metadata,merchant_id,created_at,updated_at,expires_at)
+             VALUES(?,?,?,?,?,?,?,?,?,?,?,strftime('%s','now'),strftime('%s','now'),?)",
+            params![
+                p.id,
+                p.address,
+                p.wif_enc,
+                p.derivation_index,
+                p.amount,
+                PaymentStatus::Created,
+                read_token_hash,
+                p.order_id,
+                p.description,
+                serde_json::to_string(&p.metadata).unwrap(),
+                p.merchant_id,
+                p.expires_at
+            ],
+        )?;
+        enqueue_event(&tx, WebhookEvent::PaymentCreated, &p.id)?;
+        tx.commit()
+    }
+
     pub fn find(&self, id: &str) -> SqliteResult<Option<Payment>> {
         let c = self.0.lock().unwrap();
         c.query_row(
-            "SELECT id,address,wif_enc,amount,status,created_at,updated_at,expires_at
-             FROM payments WHERE id=?",
+            &format!("SELECT {PAYMENT_COLUMNS} FROM payments WHERE id=?"),
             [id],
-            |r| {
-                Ok(Payment {
-                    id: r.get(0)?,
-                    address: r.get(1)?,
-                    wif_enc: r.get(2)?,
-                    amount: r.get(3)?,
-                    status: r.get(4)?,
-                    created_at: r.get(5)?,
-                    updated_at: r.get(6)?,
-                    expires_at: r.get(7)?,
-                })
-            },
+            payment_from_row,
+        )
+        .optional()
+    }
+
+    /// Payments matching `filter`, one keyset page at a time. Every filter
+    /// and sort column is indexed, so a page costs the same at any depth.
+    pub fn list_payments(
+        &self,
+        filter: &PaymentFilter,
+        page: &PaymentPage,
+    ) -> SqliteResult<Vec<Payment>> {
+        let mut wheres: Vec<String> = Vec::new();
+        let mut args: Vec<Value> = Vec::new();
+        if !filter.statuses.is_empty() {
+            let marks = vec!["?"; filter.statuses.len()].join(",");
+            wheres.push(format!("status IN ({marks})"));
+            args.extend(
+                filter
+                    .statuses
+                    .iter()
+                    .map(|s| Value::from(s.as_str().to_string())),
+            );
+        }
+        let bounds = [
+            ("created_at>?", filter.created_after),
+            ("created_at<?", filter.created_before),
+            ("updated_at>?", filter.updated_after),
+            ("updated_at<?", filter.updated_before),
+            ("amount_litoshis>=?", filter.min_amount.map(|v| v as i64)),
+            ("amount_litoshis<=?", filter.max_amount.map(|v| v as i64)),
+        ];
+        for (cond, bound) in bounds {
+            if let Some(v) = bound {
+                wheres.push(cond.into());
+                args.push(Value::from(v));
+            }
+        }
+        if let Some(address) = &filter.address {
+            wheres.push("address=?".into());
+            args.push(Value::from(address.clone()));
+        }
+        if let Some(merchant_id) = &filter.merchant_id {
+            wheres.push("merchant_id=?".into());
+            args.push(Value::from(merchant_id.clone()));
+        }
+        if let Some(order_id) = &filter.order_id {
+            wheres.push("order_id=?".into());
+            args.push(Value::from(order_id.clone()));
+        }
+        if let Some(search) = &filter.search {
+            let pattern = format!(
+                "%{}%",
+                search
+                    .replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_")
+            );
+            wheres.push("(order_id LIKE ? ESCAPE '\\' OR description LIKE ? ESCAPE '\\')".into());
+            args.extend([Value::from(pattern.clone()), Value::from(pattern)]);
+        }
+        if let Some((key, value)) = &filter.metadata {
+            wheres.push("CAST(json_extract(metadata,?) AS TEXT)=?".into());
+            args.extend([
+                Value::from(format!("$.\"{key}\"")),
+                Value::from(value.clone()),
+            ]);
+        }
+
+        let col = page.sort.column();
+        let (cmp, dir) = if page.descending {
+            ("<", "DESC")
+        } else {
+            (">", "ASC")
+        };
+        if let Some((value, id)) = &page.after {
+            wheres.push(format!("({col}{cmp}? OR ({col}=? AND id{cmp}?))"));
+            args.extend([
+                Value::from(*value),
+                Value::from(*value),
+                Value::from(id.clone()),
+            ]);
+        }
+        let where_sql = if wheres.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", wheres.join(" AND "))
+        };
+        args.push(Value::from(page.limit as i64));
+
+        let c = self.0.lock().unwrap();
+        let mut stmt = c.prepare(&format!(
+            "SELECT {PAYMENT_COLUMNS} FROM payments {where_sql}
+             ORDER BY {col} {dir}, id {dir} LIMIT ?"
+        ))?;
+        let rows = stmt
+            .query_map(params_from_iter(args), payment_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    /// Moves payment `id` to `to` if that is a legal transition from its
+    /// current state, queueing the webhook event it triggers. Illegal
+    /// transitions are rejected and logged; returns whether the status changed.
+    pub fn set_status(&self, id: &str, to: PaymentStatus) -> SqliteResult<bool> {
+        self.settle(id, to, None, None)
+    }
+
+    /// [`Self::set_status`] that also stores, with the transition only, how
+    /// far the settled amount is off the requested one and what is to happen
+    /// to late funds, so the webhook it triggers carries them.
+    pub fn settle(
+        &self,
+        id: &str,
+        to: PaymentStatus,
+        amount_delta: Option<i64>,
+        late_action: Option<LateAction>,
+    ) -> SqliteResult<bool> {
+        let mut c = self.0.lock().unwrap();
+        let tx = c.transaction()?;
+        let from: Option<PaymentStatus> = tx
+            .query_row("SELECT status FROM payments WHERE id=?", [id], |r| r.get(0))
+            .optional()?;
+        let changed = tx.execute(
+            &format!(
+                "UPDATE payments
+                 SET status=?,
+                     amount_delta=COALESCE(?,amount_delta),
+                     late_action=COALESCE(?,late_action),
+                     updated_at=strftime('%s','now')
+                 WHERE id=? AND status IN {}",
+                PaymentStatus::sources_sql(to)
+            ),
+            params![to, amount_delta, late_action, id],
+        )?;
+        match from {
+            Some(from) if changed > 0 => {
+                if let Some(event) = WebhookEvent::for_transition(from, to) {
+                    enqueue_event(&tx, event, id)?;
+                }
+            }
+            Some(from) if from != to => {
+                warn!(payment_id = %id, %from, %to, "rejected illegal payment status transition");
+            }
+            _ => {}
+        }
+        tx.commit()?;
+        Ok(changed > 0)
+    }
+
+    /// Payments still waiting for funds, expired ones that may yet be paid
+    /// late (those that expired at `late_since` or after) and late ones
+    /// cleared for sweeping; these are the ones the watcher subscribes to.
+    pub fn awaiting_funds(&self, late_since: i64) -> SqliteResult<Vec<Payment>> {
+        let c = self.0.lock().unwrap();
+        let mut stmt = c.prepare(&format!(
+            "SELECT {PAYMENT_COLUMNS} FROM payments
+             WHERE status IN {}
+                OR (status='expired' AND expires_at>=?)
+                OR (status='paid_late' AND (late_action='accept'
+                    OR (late_action='refund' AND refund_address IS NOT NULL)))",
+            PaymentStatus::list_sql(&[
+                PaymentStatus::Created,
+                PaymentStatus::SeenInMempool,
+                PaymentStatus::Confirming,
+                PaymentStatus::Underpaid,
+            ])
+        ))?;
+        let rows = stmt
+            .query_map([late_since], payment_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    /// Expires every open payment past its deadline, see
+    /// [`Payment::is_overdue`]; returns their ids.
+    pub fn expire_overdue(&self, now: i64, underpaid_grace: i64) -> SqliteResult<Vec<String>> {
+        let mut c = self.0.lock().unwrap();
+        let tx = c.transaction()?;
+        let ids = tx
+            .prepare(&format!(
+                "UPDATE payments
+             SET status='expired',
+                 updated_at=strftime('%s','now')
+             WHERE status IN {} AND expires_at!=0
+               AND expires_at + CASE status WHEN 'underpaid' THEN ?2 ELSE 0 END < ?1
+               AND COALESCE(received_litoshis,0) <= COALESCE(confirmed_litoshis,0)
+             RETURNING id",
+                PaymentStatus::sources_sql(PaymentStatus::Expired)
+            ))?
+            .query_map([now, underpaid_grace], |r| r.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        for id in &ids {
+            enqueue_event(&tx, WebhookEvent::PaymentExpired, id)?;
+        }
+        tx.commit()?;
+        Ok(ids)
+    }
+
+    pub fn insert_api_key(&self, k: &ApiKey) -> SqliteResult<()> {
+        self.0.lock().unwrap().execute(
+            "INSERT INTO api_keys(id,name,key_hash,scopes,merchant_id,created_at,revoked_at)
+             VALUES(?,?,?,?,?,strftime('%s','now'),NULL)",
+            params![
+                k.id,
+                k.name,
+                k.key_hash,
+                serde_json::to_string(&k.scopes).unwrap(),
+                k.merchant_id
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn api_keys(&self) -> SqliteResult<Vec<ApiKey>> {
+        let c = self.0.lock().unwrap();
+        let mut stmt = c.prepare(&format!(
+            "SELECT {API_KEY_COLUMNS} FROM api_keys ORDER BY created_at"
+        ))?;
+        let rows = stmt
+            .query_map([], api_key_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    /// Returns whether an active key with this id existed.
+    pub fn revoke_api_key(&self, id: &str) -> SqliteResult<bool> {
+        let changed = self.0.lock().unwrap().execute(
+            "UPDATE api_keys SET revoked_at=strftime('%s','now') WHERE id=? AND revoked_at IS NULL",
+            [id],
+        )?;
+        Ok(changed > 0)
+    }
+
+    /// Resolves a bearer token hash to an active API key or, for routes about
+    /// one payment, to that payment's read token.
+    pub fn authenticate(
+        &self,
+        token_hash: &str,
+        payment_id: Option<&str>,
+    ) -> SqliteResult<Option<Caller>> {
+        let c = self.0.lock().unwrap();
+        let key = c
+            .query_row(
+                &format!(
+                    "SELECT {API_KEY_COLUMNS} FROM api_keys WHERE key_hash=? AND revoked_at IS NULL"
+                ),
+                [token_hash],
+                api_key_from_row,
+            )
+            .optional()?;
+        if let Some(k) = key {
+            return Ok(Some(Caller::Key {
+                id: k.id,
+                scopes: k.scopes,
+                merchant_id: k.merchant_id,
+            }));
+        }
+        let Some(payment_id) = payment_id else {
+            return Ok(None);
+        };
+        let owns: bool = c.query_row(
+            "SELECT EXISTS(SELECT 1 FROM payments WHERE id=? AND read_token_hash=?)",
+            params![payment_id, token_hash],
+            |r| r.get(0),
+        )?;
+        Ok(owns.then(|| Caller::Payment {
+            payment_id: payment_id.to_string(),
+        }))
+    }
+
+    /// Reserves idempotency key `key` of `owner` for a request hashing to
+    /// `request_hash` and creating `payment_id`, or reports what an earlier
+    /// use of it did. Keys created before `not_before` have expired and are
+    /// dropped first; unfinished claims made before `stale_before` belonged
+    /// to a request that died and are taken over.
+    pub fn claim_idempotency_key(
+        &self,
+        owner: &str,
+        key: &str,
+        request_hash: &str,
+        payment_id: &str,
+        not_before: i64,
+        stale_before: i64,
+    ) -> SqliteResult<IdempotencyClaim> {
+        let mut c = self.0.lock().unwrap();
+        let tx = c.transaction()?;
+        tx.execute(
+            "DELETE FROM idempotency_keys WHERE created_at<?",
+            [not_before],
+        )?;
+        type Existing = (String, Option<String>, Option<i64>, i64, Option<String>);
+        let existing: Option<Existing> = tx
+            .query_row(
+                "SELECT request_hash,payment_id,completed_at,COALESCE(claimed_at,created_at),
+                        response
+                 FROM idempotency_keys WHERE owner=? AND key=?",
+                params![owner, key],
+                |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?)),
+            )
+            .optional()?;
+        let claim = match existing {
+            Some((hash, ..)) if hash != request_hash => IdempotencyClaim::Mismatch,
+            Some((_, Some(id), Some(_), _, token)) => IdempotencyClaim::Replay(id, token),
+            Some((_, _, None, claimed_at, _)) if claimed_at >= stale_before => {
+                IdempotencyClaim::InProgress
+            }
+            Some((_, id, ..)) => {
+                let id = id.unwrap_or_else(|| payment_id.to_string());
+                tx.execute(
+                    "UPDATE idempotency_keys
+                     SET payment_id=?, claimed_at=strftime('%s','now'), completed_at=NULL
+                     WHERE owner=? AND key=?",
+                    params![id, owner, key],
+                )?;
+                IdempotencyClaim::New(id)
+            }
+            None => {
+                tx.execute(
+                    "INSERT INTO idempotency_keys(owner,key,request_hash,payment_id,created_at,
+                                                  claimed_at)
+                     VALUES(?,?,?,?,strftime('%s','now'),strftime('%s','now'))",
+                    params![owner, key, request_hash, payment_id],
+                )?;
+                IdempotencyClaim::New(payment_id.to_string())
+            }
+        };
+        tx.commit()?;
+        Ok(claim)
+    }
+
+    /// Marks the request that claimed `key` done; retries replay its payment
+    /// with `read_token_enc`, the encrypted read token it handed out.
+    pub fn complete_idempotency_key(
+        &self,
+        owner: &str,
+        key: &str,
+        read_token_enc: Option<&str>,
+    ) -> SqliteResult<()> {
+        self.0.lock().unwrap().execute(
+            "UPDATE idempotency_keys SET completed_at=strftime('%s','now'), response=?
+             WHERE owner=? AND key=?",
+            params![read_token_enc, owner, key],
+        )?;
+        Ok(())
+    }
+
+    /// Frees `key` after its request failed, so a retry can run it again.
+    pub fn release_idempotency_key(&self, owner: &str, key: &str) -> SqliteResult<()> {
+        self.0.lock().unwrap().execute(
+            "DELETE FROM idempotency_keys WHERE owner=? AND key=? AND completed_at IS NULL",
+            params![owner, key],
+        )?;
+        Ok(())
+    }
+
+    /// Replaces the read token of payment `id`; the previous one stops working.
+    pub fn set_read_token_hash(&self, id: &str, token_hash: &str) -> SqliteResult<()> {
+        self.0.lock().unwrap().execute(
+            "UPDATE payments SET read_token_hash=? WHERE id=?",
+            params![token_hash, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn insert_psbt(&self, r: &PsbtRecord) -> SqliteResult<()> {
+        self.0.lock().unwrap().execute(
+            "INSERT INTO psbts(id,payment_ids,psbt,status,txid,created_at,updated_at,replaces_sweep)
+             VALUES(?,?,?,'unsigned',NULL,strftime('%s','now'),strftime('%s','now'),?)",
+            params![
+                r.id,
+                serde_json::to_string(&r.payment_ids).unwrap(),
+                r.psbt,
+                r.replaces_sweep
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn find_psbt(&self, id: &str) -> SqliteResult<Option<PsbtRecord>> {
+        let c = self.0.lock().unwrap();
+        c.query_row(
+            &format!("SELECT {PSBT_COLUMNS} FROM psbts WHERE id=?"),
+            [id],
+            psbt_from_row,
+        )
+        .optional()
+    }
+
+    /// PSBTs still waiting for a signature, oldest first.
+    pub fn unsigned_psbts(&self) -> SqliteResult<Vec<PsbtRecord>> {
+        let c = self.0.lock().unwrap();
+        let mut stmt = c.prepare(&format!(
+            "SELECT {PSBT_COLUMNS} FROM psbts WHERE status='unsigned' ORDER BY created_at"
+        ))?;
+        let rows = stmt
+            .query_map([], psbt_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    pub fn has_unsigned_psbt(&self, payment_id: &str) -> SqliteResult<bool> {
+        self.0.lock().unwrap().query_row(
+            "SELECT EXISTS(
+                 SELECT 1 FROM psbts, json_each(psbts.payment_ids) AS covered
+                 WHERE covered.value=? AND psbts.status='unsigned'
+             )",
+            [payment_id],
+            |r| r.get(0),
+        )
+    }
+
+    /// Whether an unsigned fee bump of sweep `sweep_id` awaits a signature.
+    pub fn has_replacement_psbt(&self, sweep_id: &str) -> SqliteResult<bool> {
+        self.0.lock().unwrap().query_row(
+            "SELECT EXISTS(SELECT 1 FROM psbts WHERE replaces_sweep=? AND status='unsigned')",
+            [sweep_id],
+            |r| r.get(0),
+        )
+    }
+
+    /// Takes an unsigned PSBT out of the queue as `cancelled` or `stale`,
+    /// freeing its payments for a new sweep; `false` if it was not unsigned.
+    pub fn discard_psbt(&self, id: &str, status: &str) -> SqliteResult<bool> {
+        let n = self.0.lock().unwrap().execute(
+            "UPDATE psbts
+             SET status=?,
+                 updated_at=strftime('%s','now')
+             WHERE id=? AND status='unsigned'",
+            params![status, id],
+        )?;
+        Ok(n == 1)
+    }
+
+    pub fn mark_psbt_broadcast(&self, id: &str, txid: &str) -> SqliteResult<()> {
+        self.0.lock().unwrap().execute(
+            "UPDATE psbts
+             SET status='broadcast',
+                 txid=?,
+                 updated_at=strftime('%s','now')
+             WHERE id=?",
+            params![txid, id],
+        )?;
+        Ok(())
+    }
+
+    /// Whether a broadcast, unconfirmed sweep already spends this payment's funds.
+    pub fn in_active_sweep(&self, payment_id: &str) -> SqliteResult<bool> {
+        self.0.lock().unwrap().query_row(
+            "SELECT EXISTS(
+                 SELECT 1 FROM sweeps, json_each(sweeps.payment_ids) AS covered
+                 WHERE covered.value=? AND sweeps.status='broadcast'
+             )",
+            [payment_id],
+            |r| r.get(0),
+        )
+    }
+
+    /// Stores a freshly broadcast sweep and links the payments it covers to
+    /// its txid, atomically. Payment statuses are left alone until it confirms.
+    pub fn record_sweep(&self, sw: &Sweep) -> SqliteResult<()> {
+        let mut c = self.0.lock().unwrap();
+        let tx = c.transaction()?;
+        tx.execute(
+            "INSERT INTO sweeps(id,txid,raw_hex,fee,fee_rate,inputs,payment_ids,status,
+                                broadcast_height,fee_bumps,broadcast_attempts,last_broadcast_at,
+                                confirmed_height,created_at,updated_at)
+             VALUES(?,?,?,?,?,?,?,'broadcast',?,'[]',1,strftime('%s','now'),NULL,
+                    strftime('%s','now'),strftime('%s','now'))",
+            params![
+                sw.id,
+                sw.txid,
+                sw.raw_hex,
+                sw.fee,
+                sw.fee_rate,
+                serde_json::to_string(&sw.inputs).unwrap(),
+                serde_json::to_string(&sw.payment_ids).unwrap(),
+                sw.broadcast_height,
+            ],
+        )?;
+        for id in &sw.payment_ids {
+            tx.execute(
+                "UPDATE payments
+                 SET sweep_txid=?,
+                     updated_at=strftime('%s','now')
+                 WHERE id=?",
+                params![sw.txid, id],
+            )?;
+        }
+        tx.commit()
+    }
+
+    /// Sweeps broadcast but not yet confirmed.
+    pub fn active_sweeps(&self) -> SqliteResult<Vec<Sweep>> {
+        let c = self.0.lock().unwrap();
+        let mut stmt = c.prepare(&format!(
+            "SELECT {SWEEP_COLUMNS} FROM sweeps WHERE status='broadcast' ORDER BY created_at"
+        ))?;
+        let rows = stmt
+            .query_map([], sweep_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    pub fn note_rebroadcast(&self, id: &str) -> SqliteResult<()> {
+        self.0.lock().unwrap().execute(
+            "UPDATE sweeps
+             SET broadcast_attempts=broadcast_attempts+1,
+                 last_broadcast_at=strftime('%s','now'),
+                 updated_at=strftime('%s','now')
+             WHERE id=?",
+            [id],
+        )?;
+        Ok(())
+    }
+
+    /// Swaps in an RBF replacement for sweep `id`: the current txid moves to
+    /// the fee bump history and its payments are relinked to the new txid.
+    pub fn replace_sweep(
+        &self,
+        id: &str,
+        txid: &str,
+        raw_hex: &str,
+        fee: u64,
+        fee_rate: u64,
+        height: u64,
+    ) -> SqliteResult<()> {
+        let mut c = self.0.lock().unwrap();
+        let tx = c.transaction()?;
+        tx.execute(
+            "UPDATE payments
+             SET sweep_txid=?,
+                 updated_at=strftime('%s','now')
+             WHERE sweep_txid=(SELECT txid FROM sweeps WHERE id=?)",
+            params![txid, id],
+        )?;
+        tx.execute(
+            "UPDATE sweeps
+             SET fee_bumps=json_insert(fee_bumps,'$[#]',json_object(
+                     'txid',txid,'fee',fee,'fee_rate',fee_rate,
+                     'height',?,'replaced_at',CAST(strftime('%s','now') AS INTEGER))),
+                 txid=?,
+                 raw_hex=?,
+                 fee=?,
+                 fee_rate=?,
+                 broadcast_height=?,
+                 broadcast_attempts=1,
+                 last_broadcast_at=strftime('%s','now'),
+                 updated_at=strftime('%s','now')
+             WHERE id=?",
+            params![height, txid, raw_hex, fee, fee_rate, height, id],
+        )?;
+        tx.commit()
+    }
+
+    /// Marks a sweep confirmed at `height` and moves its settled payments to
+    /// `swept`. `txid` is the transaction that was mined: the current one or
+    /// one it replaced via RBF, which then becomes the sweep's (and its
+    /// payments') txid again. Payments in any other state (e.g. late funds of
+    /// an expired payment) keep their status; the rejection is logged.
+    /// Returns the ids of the payments that became `swept`.
+    pub fn confirm_sweep(&self, id: &str, txid: &str, height: u64) -> SqliteResult<Vec<String>> {
+        let mut c = self.0.lock().unwrap();
+        let tx = c.transaction()?;
+        tx.execute(
+            "UPDATE payments
+             SET sweep_txid=?1,
+                 updated_at=strftime('%s','now')
+             WHERE sweep_txid=(SELECT txid FROM sweeps WHERE id=?2) AND sweep_txid!=?1",
+            params![txid, id],
+        )?;
+        // a mined replaced transaction paid the fee it was recorded with
+        tx.execute(
+            "UPDATE sweeps
+             SET status='confirmed',
+                 confirmed_height=?1,
+                 fee=COALESCE((SELECT json_extract(b.value,'$.fee') FROM json_each(fee_bumps) b
+                               WHERE json_extract(b.value,'$.txid')=?2 AND txid!=?2), fee),
+                 fee_rate=COALESCE((SELECT json_extract(b.value,'$.fee_rate')
+                                    FROM json_each(fee_bumps) b
+                                    WHERE json_extract(b.value,'$.txid')=?2 AND txid!=?2), fee_rate),
+                 txid=?2,
+                 updated_at=strftime('%s','now')
+             WHERE id=?3",
+            params![height, txid, id],
+        )?;
+        let txid = txid.to_string();
+        let swept = tx
+            .prepare(&format!(
+                "UPDATE payments
+                 SET status='swept',
+                     updated_at=strftime('%s','now')
+                 WHERE sweep_txid=? AND status IN {}
+                 RETURNING id",
+                PaymentStatus::sources_sql(PaymentStatus::Swept)
+            ))?
+            .query_map([&txid], |r| r.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        for payment_id in &swept {
+            enqueue_event(&tx, WebhookEvent::SweepConfirmed, payment_id)?;
+        }
+        let rejected = tx
+            .prepare("SELECT id,status FROM payments WHERE sweep_txid=? AND status!='swept'")?
+            .query_map([&txid], |r| {
+                Ok((r.get::<_, String>(0)?, r.get::<_, PaymentStatus>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (payment_id, from) in rejected {
+            warn!(%payment_id, %from, to = %PaymentStatus::Swept, %txid,
+                "rejected illegal payment status transition");
+        }
+        tx.commit()?;
+        Ok(swept)
+    }
+
+    /// Gives up on a sweep and unlinks its payments so the sweeper rebuilds
+    /// them from their current UTXOs.
+    pub fn fail_sweep(&self, id: &str) -> SqliteResult<()> {
+        let mut c = self.0.lock().unwrap();
+        let tx = c.transaction()?;
+        tx.execute(
+            "UPDATE payments
+             SET sweep_txid=NULL,
+                 updated_at=strftime('%s','now')
+             WHERE sweep_txid=(SELECT txid FROM sweeps WHERE id=?)",
+            [id],
+        )?;
+        tx.execute(
+            "UPDATE sweeps
+             SET status='failed',
+                 updated_at=strftime('%s','now')
+             WHERE id=?",
+            [id],
+        )?;
+        tx.commit()
+    }
+
+    /// Pending webhook events whose next attempt is due, oldest first.
+    pub fn due_webhook_events(&self, now: i64, limit: usize) -> SqliteResult<Vec<OutboxEvent>> {
+        let c = self.0.lock().unwrap();
+        let mut stmt = c.prepare(&format!(
+            "SELECT {OUTBOX_COLUMNS} FROM webhook_events
+             WHERE status='pending' AND next_attempt_at<=?
+             ORDER BY created_at, rowid
+             LIMIT ?"
+        ))?;
+        let rows = stmt
+            .query_map(params![now, limit], outbox_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    /// Logs a delivery attempt and moves its event on according to `outcome`.
+    pub fn record_webhook_attempt(
+        &self,
+        a: &WebhookAttempt,
+        outcome: DeliveryOutcome,
+    ) -> SqliteResult<()> {
+        let mut c = self.0.lock().unwrap();
+        let tx = c.transaction()?;
+        tx.execute(
+            "INSERT INTO webhook_attempts(event_id,attempted_at,status_code,latency_ms,response,error)
+             VALUES(?,?,?,?,?,?)",
+            params![
+                a.event_id,
+                a.attempted_at,
+                a.status_code,
+                a.latency_ms,
+                a.response,
+                a.error
+            ],
+        )?;
+        let (status, next_attempt_at) = match outcome {
+            DeliveryOutcome::Delivered => ("delivered", None),
+            DeliveryOutcome::Retry(at) => ("pending", Some(at)),
+            DeliveryOutcome::Dead => ("dead", None),
+        };
+        let last_error = match (&a.error, a.status_code) {
+            (Some(e), _) => Some(e.clone()),
+            (None, Some(code)) if outcome != DeliveryOutcome::Delivered => {
+                Some(format!("HTTP {code}"))
+            }
+            _ => None,
+        };
+        tx.execute(
+            "UPDATE webhook_events
+             SET status=?,
+                 attempts=attempts+1,
+                 next_attempt_at=COALESCE(?,next_attempt_at),
+                 last_error=?,
+                 delivered_at=CASE WHEN ?='delivered' THEN ? ELSE delivered_at END,
+                 updated_at=strftime('%s','now')
+             WHERE id=?",
+            params![
+                status,
+                next_attempt_at,
+                last_error,
+                status,
+                a.attempted_at,
+                a.event_id
+            ],
+        )?;
+        tx.commit()
+    }
+
+    /// Outbox events matching `filter`, newest first, after the
+    /// `(created_at, id)` of the previous page's last row.
+    pub fn list_webhook_events(
+        &self,
+        filter: &WebhookEventFilter,
+        after: Option<&(i64, String)>,
+        limit: usize,
+    ) -> SqliteResult<Vec<OutboxEvent>> {
+        let mut wheres: Vec<String> = Vec::new();
+        let mut args: Vec<Value> = Vec::new();
+        if let Some(merchant_id) = &filter.merchant_id {
+            wheres.push("merchant_id=?".into());
+            args.push(Value::from(merchant_id.clone()));
+        }
+        if let Some(payment_id) = &filter.payment_id {
+            wheres.push("payment_id=?".into());
+            args.push(Value::from(payment_id.clone()));
+        }
+        if !filter.statuses.is_empty() {
+            let marks = vec!["?"; filter.statuses.len()].join(",");
+            wheres.push(format!("status IN ({marks})"));
+            args.extend(filter.statuses.iter().cloned().map(Value::from));
+        }
+        let bounds = [
+            ("created_at>?", filter.created_after),
+            ("created_at<?", filter.created_before),
+        ];
+        for (cond, bound) in bounds {
+            if let Some(v) = bound {
+                wheres.push(cond.into());
+                args.push(Value::from(v));
+            }
+        }
+        if let Some((created_at, id)) = after {
+            wheres.push("(created_at<? OR (created_at=? AND id<?))".into());
+            args.extend([
+                Value::from(*created_at),
+                Value::from(*created_at),
+                Value::from(id.clone()),
+            ]);
+        }
+        let where_sql = if wheres.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", wheres.join(" AND "))
+        };
+        args.push(Value::from(limit as i64));
+
+        let c = self.0.lock().unwrap();
+        let mut stmt = c.prepare(&format!(
+            "SELECT {OUTBOX_COLUMNS} FROM webhook_events {where_sql}
+             ORDER BY created_at DESC, id DESC LIMIT ?"
+        ))?;
+        let rows = stmt
+            .query_map(params_from_iter(args), outbox_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    pub fn find_webhook_event(&self, id: &str) -> SqliteResult<Option<OutboxEvent>> {
+        let c = self.0.lock().unwrap();
+        c.query_row(
+            &format!("SELECT {OUTBOX_COLUMNS} FROM webhook_events WHERE id=?"),
+            [id],
+            outbox_from_row,
         )
         .optional()
     }
 
-    pub fn all(&self) -> SqliteResult<Vec<Payment>> {
+    /// Every delivery attempt of one event, oldest first.
+    pub fn webhook_attempts(&self, event_id: &str) -> SqliteResult<Vec<WebhookAttempt>> {
         let c = self.0.lock().unwrap();
         let mut stmt = c.prepare(
-            "SELECT id,address,wif_enc,amount,status,created_at,updated_at,expires_at
-             FROM payments",
+            "SELECT event_id,attempted_at,status_code,latency_ms,response,error
+             FROM webhook_attempts WHERE event_id=? ORDER BY id",
         )?;
         let rows = stmt
-            .query_map([], |r| {
-                Ok(Payment {
-                    id: r.get(0)?,
-                    address: r.get(1)?,
-                    wif_enc: r.get(2)?,
-                    amount: r.get(3)?,
-                    status: r.get(4)?,
-                    created_at: r.get(5)?,
-                    updated_at: r.get(6)?,
-                    expires_at: r.get(7)?,
+            .query_map([event_id], |r| {
+                Ok(WebhookAttempt {
+                    event_id: r.get(0)?,
+                    attempted_at: r.get(1)?,
+                    status_code: r.get(2)?,
+                    latency_ms: r.get(3)?,
+                    response: r.get(4)?,
+                    error: r.get(5)?,
                 })
             })?
             .collect::<Result<Vec<_>, _>>()?;
         Ok(rows)
     }
 
-    pub fn mark_completed(&self, id: &str) -> SqliteResult<()> {
-        self.0.lock().unwrap().execute(
-            "UPDATE payments
-             SET status='completed',
+    /// Queues event `id` for immediate redelivery with a fresh retry budget,
+    /// whatever its status; returns whether it exists.
+    pub fn replay_webhook_event(&self, id: &str) -> SqliteResult<bool> {
+        let changed = self.0.lock().unwrap().execute(
+            "UPDATE webhook_events
+             SET status='pending',
+                 attempts=0,
+                 next_attempt_at=strftime('%s','now'),
                  updated_at=strftime('%s','now')
              WHERE id=?",
             [id],
         )?;
-        Ok(())
+        Ok(changed > 0)
     }
 
-    pub fn mark_expired(&self, id: &str) -> SqliteResult<()> {
+    /// Queues for immediate redelivery every event created in
+    /// `[since, until)`, of `merchant_id` if given, that is `dead` or still
+    /// pending after a failed attempt; returns how many.
+    pub fn replay_failed_webhook_events(
+        &self,
+        since: i64,
+        until: i64,
+        merchant_id: Option<&str>,
+    ) -> SqliteResult<usize> {
         self.0.lock().unwrap().execute(
-            "UPDATE payments
-             SET status='expired',
+            "UPDATE webhook_events
+             SET status='pending',
+                 attempts=0,
+                 next_attempt_at=strftime('%s','now'),
+                 updated_at=strftime('%s','now')
+             WHERE created_at>=? AND created_at<?
+               AND (?3 IS NULL OR merchant_id=?3)
+               AND (status='dead' OR (status='pending' AND attempts>0))",
+            params![since, until, merchant_id],
+        )
+    }
+
+    pub fn insert_merchant(&self, m: &Merchant) -> SqliteResult<()> {
+        self.0.lock().unwrap().execute(
+            "INSERT INTO merchants(id,name,payout_address,payout_xpub,webhook_url,webhook_secret,
+                                   confirmations,default_ttl,created_at,updated_at)
+             VALUES(?,?,?,?,?,?,?,?,strftime('%s','now'),strftime('%s','now'))",
+            params![
+                m.id,
+                m.name,
+                m.payout_address,
+                m.payout_xpub,
+                m.webhook_url,
+                m.webhook_secret,
+                m.confirmations,
+                m.default_ttl
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Writes every setting of `m`; `payout_index` restarts at 0 when the
+    /// payout xpub changes.
+    pub fn update_merchant(&self, m: &Merchant) -> SqliteResult<bool> {
+        let changed = self.0.lock().unwrap().execute(
+            "UPDATE merchants
+             SET name=?1,
+                 payout_address=?2,
+                 payout_xpub=?3,
+                 payout_index=CASE WHEN payout_xpub IS ?3 THEN payout_index ELSE 0 END,
+                 webhook_url=?4,
+                 webhook_secret=?5,
+                 confirmations=?6,
+                 default_ttl=?7,
                  updated_at=strftime('%s','now')
-             WHERE id=? AND status='pending'",
+             WHERE id=?8",
+            params![
+                m.name,
+                m.payout_address,
+                m.payout_xpub,
+                m.webhook_url,
+                m.webhook_secret,
+                m.confirmations,
+                m.default_ttl,
+                m.id
+            ],
+        )?;
+        Ok(changed > 0)
+    }
+
+    pub fn merchants(&self) -> SqliteResult<Vec<Merchant>> {
+        let c = self.0.lock().unwrap();
+        let mut stmt = c.prepare(&format!(
+            "SELECT {MERCHANT_COLUMNS} FROM merchants ORDER BY created_at, id"
+        ))?;
+        let rows = stmt
+            .query_map([], merchant_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    pub fn find_merchant(&self, id: &str) -> SqliteResult<Option<Merchant>> {
+        let c = self.0.lock().unwrap();
+        c.query_row(
+            &format!("SELECT {MERCHANT_COLUMNS} FROM merchants WHERE id=?"),
             [id],
+            merchant_from_row,
+        )
+        .optional()
+    }
+
+    /// Marks receive index `index` of merchant `id`'s payout xpub used, once a
+    /// sweep to it is recorded; the next sweep derives a fresh address.
+    pub fn use_payout_index(&self, id: &str, index: u32) -> SqliteResult<()> {
+        self.0.lock().unwrap().execute(
+            "UPDATE merchants SET payout_index=MAX(payout_index, ?+1) WHERE id=?",
+            params![index, id],
         )?;
         Ok(())
     }
+
+    /// Pushes a pending event's next attempt to `at` without counting an
+    /// attempt, e.g. while its merchant has no webhook URL.
+    pub fn defer_webhook_event(&self, id: &str, at: i64) -> SqliteResult<()> {
+        self.0.lock().unwrap().execute(
+            "UPDATE webhook_events SET next_attempt_at=? WHERE id=? AND status='pending'",
+            params![at, id],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Db {
+    /// A fresh, fully migrated in-memory database.
+    pub fn memory() -> Self {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut conn).unwrap();
+        Db(Arc::new(Mutex::new(conn)))
+    }
+
+    /// Inserts a 0.001 LTC payment of the `default` merchant at address
+    /// `ltc1q<id>`.
+    pub fn insert_test_payment(&self, id: &str, status: PaymentStatus, expires_at: i64) {
+        self.0
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO payments(id,address,wif_enc,amount_litoshis,status,created_at,
+                                      updated_at,expires_at,merchant_id)
+                 VALUES(?,?,'',100000,?,0,0,?,'default')",
+                params![id, format!("ltc1q{id}"), status, expires_at],
+            )
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use PaymentStatus::*;
+
+    #[test]
+    fn settled_and_swept_payments_only_move_forward() {
+        assert!(!Expired.can_become(Swept));
+        assert!(Expired.can_become(PaidLate));
+        assert!(PaidLate.can_become(Swept));
+        for to in PaymentStatus::ALL {
+            assert!(!Swept.can_become(to), "swept -> {to}");
+            assert!(!to.can_become(to), "{to} -> {to}");
+        }
+        // only funds vanishing before they settle take a payment back
+        assert_eq!(
+            PaymentStatus::sources_sql(Created),
+            "('seen_in_mempool','confirming')"
+        );
+        for from in [Paid, Overpaid, PaidLate] {
+            assert_eq!(from.next(), [Swept]);
+            assert!(!from.is_open());
+        }
+        assert!(Underpaid.can_become(Paid));
+        assert!(!Paid.can_become(Underpaid));
+    }
+
+    #[test]
+    fn sources_sql_lists_every_state_that_may_move() {
+        assert_eq!(
+            PaymentStatus::sources_sql(Swept),
+            "('paid','overpaid','paid_late')"
+        );
+        assert_eq!(
+            PaymentStatus::sources_sql(Expired),
+            "('created','underpaid')"
+        );
+        assert_eq!(PaymentStatus::sources_sql(PaidLate), "('expired')");
+        assert_eq!(PaymentStatus::sources_sql(SeenInMempool), "('created')");
+    }
+
+    #[test]
+    fn detected_funds_hold_off_expiry() {
+        let db = Db::memory();
+        for (id, status) in [
+            ("c", Created),
+            ("m", SeenInMempool),
+            ("f", Confirming),
+            ("u", Underpaid),
+            ("t", Underpaid),
+        ] {
+            db.insert_test_payment(id, status, 100);
+        }
+        // an underpayment with a top-up still in the mempool
+        db.0.lock()
+            .unwrap()
+            .execute(
+                "UPDATE payments SET received_litoshis=90000, confirmed_litoshis=50000 WHERE id='t'",
+                [],
+            )
+            .unwrap();
+        for id in ["m", "f", "t"] {
+            assert!(!db.find(id).unwrap().unwrap().is_overdue(200, 0), "{id}");
+        }
+        let mut expired = db.expire_overdue(200, 0).unwrap();
+        expired.sort();
+        assert_eq!(expired, ["c", "u"]);
+        assert_eq!(db.find("f").unwrap().unwrap().status, Confirming);
+        assert!(db.set_status("f", Paid).unwrap());
+    }
+
+    #[test]
+    fn set_status_rejects_disallowed_transitions() {
+        let db = Db::memory();
+        db.insert_test_payment("e", Expired, 0);
+        db.insert_test_payment("s", Swept, 0);
+        assert!(!db.set_status("e", Swept).unwrap());
+        assert!(!db.set_status("s", Paid).unwrap());
+        assert!(!db.set_status("s", Created).unwrap());
+        assert_eq!(db.find("e").unwrap().unwrap().status, Expired);
+        assert_eq!(db.find("s").unwrap().unwrap().status, Swept);
+        assert!(db.set_status("e", PaidLate).unwrap());
+        assert_eq!(db.find("e").unwrap().unwrap().status, PaidLate);
+    }
+
+    #[test]
+    fn empty_history_does_not_flag_double_spends() {
+        let db = Db::memory();
+        db.insert_test_payment("p", Paid, 0);
+        let outputs = [("a".to_string(), 0, 60_000), ("b".to_string(), 1, 40_000)];
+        let history = [("a".into(), 100, Some(1000)), ("b".into(), 0, None)];
+        db.record_payment_txs("p", &outputs, &history).unwrap();
+
+        db.record_payment_txs("p", &[], &[]).unwrap();
+        let txs = db.payment_txs("p").unwrap();
+        assert!(txs.iter().all(|t| !t.double_spent));
+        assert!(txs[0].confirmed_at.is_some());
+
+        // a history that lost one transaction still flags it
+        db.record_payment_txs("p", &[], &history[..1]).unwrap();
+        let txs = db.payment_txs("p").unwrap();
+        assert!(!txs[0].double_spent);
+        assert!(txs[1].double_spent);
+    }
+
+    #[test]
+    fn replacement_psbt_pending_until_discarded() {
+        let db = Db::memory();
+        db.insert_psbt(&PsbtRecord {
+            id: "bump".into(),
+            payment_ids: vec!["p".into()],
+            psbt: String::new(),
+            status: "unsigned".into(),
+            txid: None,
+            replaces_sweep: Some("sw".into()),
+            created_at: 0,
+            updated_at: 0,
+        })
+        .unwrap();
+        assert!(db.has_replacement_psbt("sw").unwrap());
+        assert!(!db.has_replacement_psbt("other").unwrap());
+        let stored = db.find_psbt("bump").unwrap().unwrap();
+        assert_eq!(stored.replaces_sweep.as_deref(), Some("sw"));
+
+        assert!(db.discard_psbt("bump", "stale").unwrap());
+        assert!(!db.has_replacement_psbt("sw").unwrap());
+    }
+
+    #[test]
+    fn settle_stores_delta_and_late_action_with_the_transition_only() {
+        let db = Db::memory();
+        db.insert_test_payment("s", Swept, 0);
+        assert!(!db
+            .settle("s", PaidLate, Some(5), Some(LateAction::Accept))
+            .unwrap());
+        let s = db.find("s").unwrap().unwrap();
+        assert_eq!((s.amount_delta, s.late_action), (None, None));
+
+        db.insert_test_payment("e", Expired, 0);
+        assert!(db
+            .settle("e", PaidLate, Some(-3), Some(LateAction::Review))
+            .unwrap());
+        let e = db.find("e").unwrap().unwrap();
+        assert_eq!(
+            (e.amount_delta, e.late_action),
+            (Some(-3), Some(LateAction::Review))
+        );
+    }
+
+    #[test]
+    fn replay_returns_the_original_read_token() {
+        let db = Db::memory();
+        let claim = || {
+            db.claim_idempotency_key("k", "key", "h", "p", 0, 0)
+                .unwrap()
+        };
+        assert_eq!(claim(), IdempotencyClaim::New("p".into()));
+        db.complete_idempotency_key("k", "key", Some("sealed"))
+            .unwrap();
+        assert_eq!(
+            claim(),
+            IdempotencyClaim::Replay("p".into(), Some("sealed".into()))
+        );
+        assert_eq!(
+            claim(),
+            IdempotencyClaim::Replay("p".into(), Some("sealed".into()))
+        );
+    }
 }
@@ -1,20 +1,220 @@
 use anyhow::{anyhow, bail, Context, Result};
 use electrum_client::{Client, ConfigBuilder, ElectrumApi, Param};
 use once_cell::sync::Lazy;
-use serde_json::Value;
+use serde_json::{json, Value};
+use std::env;
+use std::sync::Mutex;
 use std::thread;
-use std::time::Duration;
-use tokio::sync::Mutex as AsyncMutex;
-use tracing::{debug, error, info, trace};
+use std::time::{Duration, Instant};
+use tokio::time::interval;
+use tracing::{debug, error, info, trace, warn};
 
-fn connect_once(host: &str, port: &str, scheme: &str) -> Result<Client> {
-    let url = format!("{scheme}://{host}:{port}");
+/// A single upstream Electrum endpoint.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub scheme: String,
+}
+
+impl ServerConfig {
+    /// Parses `ssl://host:port` / `tcp://host:port`; a missing scheme means tcp.
+    pub fn parse(s: &str) -> Result<Self> {
+        let (scheme, rest) = s.trim().split_once("://").unwrap_or(("tcp", s.trim()));
+        let scheme = scheme.to_lowercase();
+        if scheme != "tcp" && scheme != "ssl" {
+            bail!("electrum scheme must be tcp or ssl, got {scheme}");
+        }
+        let (host, port) = rest
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("electrum server {s} is missing a port"))?;
+        let port = port
+            .parse::<u16>()
+            .with_context(|| format!("invalid port in {s}"))?;
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            scheme,
+        })
+    }
+
+    pub fn url(&self) -> String {
+        format!("{}://{}:{}", self.scheme, self.host, self.port)
+    }
+}
+
+/// Upstream Electrum servers and failover policy, read once from the environment.
+#[derive(Debug, Clone)]
+pub struct ElectrumConfig {
+    pub servers: Vec<ServerConfig>,
+    pub validate_domain: bool,
+    pub timeout: u8,
+    pub cooldown: Duration,
+    pub max_failures: u32,
+    pub max_tip_lag: u64,
+    pub probe_interval: Duration,
+}
+
+impl ElectrumConfig {
+    pub fn from_env() -> Result<Self> {
+        let servers = match env::var("ELECTRUM_SERVERS") {
+            Ok(list) if !list.trim().is_empty() => list
+                .split(',')
+                .filter(|s| !s.trim().is_empty())
+                .map(ServerConfig::parse)
+                .collect::<Result<Vec<_>>>()?,
+            _ => {
+                let host =
+                    env::var("ELECTRUM_HOST").unwrap_or_else(|_| "electrum.ltc.xurious.com".into());
+                let port = env::var("ELECTRUM_PORT")
+                    .unwrap_or_else(|_| "50001".into())
+                    .parse::<u16>()
+                    .context("ELECTRUM_PORT must be a port number")?;
+                let scheme = env::var("ELECTRUM_SCHEME").unwrap_or_else(|_| "tcp".into());
+                vec![ServerConfig::parse(&format!("{scheme}://{host}:{port}"))?]
+            }
+        };
+        if servers.is_empty() {
+            bail!("no electrum servers configured");
+        }
+        let validate_domain = env::var("ELECTRUM_VALIDATE_DOMAIN")
+            .map(|v| matches!(v.to_lowercase().as_str(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+        let timeout = env::var("ELECTRUM_TIMEOUT")
+            .unwrap_or_else(|_| "15".into())
+            .parse::<u8>()
+            .context("ELECTRUM_TIMEOUT must be 0-255 seconds")?;
+        let cooldown = env::var("ELECTRUM_COOLDOWN")
+            .unwrap_or_else(|_| "60".into())
+            .parse::<u64>()
+            .context("ELECTRUM_COOLDOWN must be seconds")?;
+        let max_failures = env::var("ELECTRUM_MAX_FAILURES")
+            .unwrap_or_else(|_| "3".into())
+            .parse::<u32>()
+            .context("ELECTRUM_MAX_FAILURES must be a number")?;
+        let max_tip_lag = env::var("ELECTRUM_MAX_TIP_LAG")
+            .unwrap_or_else(|_| "1".into())
+            .parse::<u64>()
+            .context("ELECTRUM_MAX_TIP_LAG must be a number of blocks")?;
+        let probe_interval = env::var("ELECTRUM_PROBE_INTERVAL")
+            .unwrap_or_else(|_| "30".into())
+            .parse::<u64>()
+            .context("ELECTRUM_PROBE_INTERVAL must be seconds")?;
+        Ok(Self {
+            servers,
+            validate_domain,
+            timeout,
+            cooldown: Duration::from_secs(cooldown),
+            max_failures: max_failures.max(1),
+            max_tip_lag,
+            probe_interval: Duration::from_secs(probe_interval.max(1)),
+        })
+    }
+}
+
+static CONFIG: Lazy<ElectrumConfig> = Lazy::new(|| match ElectrumConfig::from_env() {
+    Ok(cfg) => {
+        let urls: Vec<String> = cfg.servers.iter().map(|s| s.url()).collect();
+        info!(servers = ?urls, "Loaded electrum config from environment");
+        cfg
+    }
+    Err(e) => {
+        error!("Invalid electrum config: {:#}", e);
+        panic!("invalid ELECTRUM_* configuration");
+    }
+});
+
+/// Rolling health figures for one server; used to rank servers for each call.
+#[derive(Debug, Default)]
+struct Health {
+    /// exponentially weighted round-trip time in milliseconds
+    latency_ms: f64,
+    /// exponentially weighted share of failed calls (0.0 - 1.0)
+    error_rate: f64,
+    consecutive_failures: u32,
+    tip: u64,
+    cooldown_until: Option<Instant>,
+}
+
+impl Health {
+    fn cooling(&self, now: Instant) -> bool {
+        self.cooldown_until.is_some_and(|t| t > now)
+    }
+
+    fn score(&self) -> f64 {
+        (self.latency_ms + 1.0) * (1.0 + 10.0 * self.error_rate)
+    }
+}
+
+struct Server {
+    cfg: ServerConfig,
+    pool: Mutex<Vec<Client>>,
+    health: Mutex<Health>,
+}
+
+impl Server {
+    fn record_success(&self, elapsed: Duration) {
+        let ms = elapsed.as_secs_f64() * 1000.0;
+        let mut h = self.health.lock().unwrap();
+        h.latency_ms = if h.latency_ms == 0.0 {
+            ms
+        } else {
+            0.8 * h.latency_ms + 0.2 * ms
+        };
+        h.error_rate *= 0.8;
+        h.consecutive_failures = 0;
+        h.cooldown_until = None;
+    }
+
+    fn record_failure(&self) {
+        let mut h = self.health.lock().unwrap();
+        h.error_rate = 0.8 * h.error_rate + 0.2;
+        h.consecutive_failures += 1;
+        if h.consecutive_failures >= CONFIG.max_failures {
+            warn!(url = %self.cfg.url(), failures = h.consecutive_failures, "electrum server cooling down");
+            h.cooldown_until = Some(Instant::now() + CONFIG.cooldown);
+        }
+    }
+
+    fn record_tip(&self, tip: u64) {
+        self.health.lock().unwrap().tip = tip;
+    }
+
+    /// Takes a pooled connection or dials a new one. A dial failure puts the
+    /// server straight into cooldown.
+    fn client(&self) -> Result<Client> {
+        if let Some(c) = self.pool.lock().unwrap().pop() {
+            return Ok(c);
+        }
+        connect_once(&self.cfg).inspect_err(|_| {
+            let mut h = self.health.lock().unwrap();
+            h.error_rate = 0.8 * h.error_rate + 0.2;
+            h.consecutive_failures = CONFIG.max_failures;
+            h.cooldown_until = Some(Instant::now() + CONFIG.cooldown);
+        })
+    }
+}
+
+static SERVERS: Lazy<Vec<Server>> = Lazy::new(|| {
+    CONFIG
+        .servers
+        .iter()
+        .map(|cfg| Server {
+            cfg: cfg.clone(),
+            pool: Mutex::new(Vec::new()),
+            health: Mutex::new(Health::default()),
+        })
+        .collect()
+});
+
+fn connect_once(server: &ServerConfig) -> Result<Client> {
+    let url = server.url();
     debug!(%url, "connecting");
 
     let cfg = ConfigBuilder::new()
-        .timeout(Some(15))
+        .timeout(Some(CONFIG.timeout))
         .retry(0)
-        .validate_domain(false)
+        .validate_domain(CONFIG.validate_domain)
         .build();
 
     let c = Client::from_config(&url, cfg).with_context(|| format!("dial {url}"))?;
@@ -32,13 +232,29 @@ fn connect_once(host: &str, port: &str, scheme: &str) -> Result<Client> {
     Ok(c)
 }
 
-fn fresh_client() -> Result<Client> {
-    connect_once("electrum.ltc.xurious.com", "50001", "tcp")
-        .map_err(|e| anyhow!("connection failed").context(e))
+/// Server indices in preference order. Servers in cooldown or whose tip lags
+/// the best known tip by more than `ELECTRUM_MAX_TIP_LAG` are demoted to the
+/// back; if nothing is healthy the demoted ones are still tried.
+fn ranked_servers() -> Vec<usize> {
+    let now = Instant::now();
+    let best_tip = SERVERS
+        .iter()
+        .map(|s| s.health.lock().unwrap().tip)
+        .max()
+        .unwrap_or(0);
+    let mut ranked: Vec<(bool, f64, usize)> = SERVERS
+        .iter()
+        .enumerate()
+        .map(|(i, s)| {
+            let h = s.health.lock().unwrap();
+            let lagging = h.tip > 0 && h.tip + CONFIG.max_tip_lag < best_tip;
+            (h.cooling(now) || lagging, h.score(), i)
+        })
+        .collect();
+    ranked.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)));
+    ranked.into_iter().map(|(_, _, i)| i).collect()
 }
 
-static POOL: Lazy<AsyncMutex<Vec<Client>>> = Lazy::new(|| AsyncMutex::new(Vec::new()));
-
 fn json_param(v: &Value) -> Param {
     match v {
         Value::String(s) => Param::String(s.clone()),
@@ -64,26 +280,45 @@ fn to_params(v: &[Value]) -> Vec<Param> {
     v.iter().map(json_param).collect()
 }
 
-/// synchronous RPC; pulls a client from the pool, performs the call, returns it
+/// Performs one call against one server, updating its health figures.
+fn call_on(server: &Server, method: &str, params: &[Value]) -> Result<Value> {
+    let client = server.client()?;
+    let started = Instant::now();
+    match client.raw_call(method, to_params(params)) {
+        Ok(v) => {
+            server.record_success(started.elapsed());
+            if method == "blockchain.headers.subscribe" {
+                if let Some(tip) = v["height"].as_u64() {
+                    server.record_tip(tip);
+                }
+            }
+            // headers.subscribe leaves the connection subscribed; drop queued
+            // notifications so pooled clients don't grow without bound
+            while let Ok(Some(_)) = client.block_headers_pop_raw() {}
+            server.pool.lock().unwrap().push(client);
+            Ok(v)
+        }
+        Err(e) => {
+            server.record_failure();
+            Err(anyhow!("{method} on {}: {e:?}", server.cfg.url()))
+        }
+    }
+}
+
+/// synchronous RPC; walks the servers in health order, failing over on error
 fn rpc_sync(method: &str, params: &[Value]) -> Result<Value> {
+    let order = ranked_servers();
     for attempt in 1..=3 {
-        let mut guard = POOL.blocking_lock();
-        let client = match guard.pop() {
-            Some(c) => c,
-            None => fresh_client()?,
-        };
-        drop(guard);
-
-        trace!(%method, ?params, attempt, "rpc attempt");
-        match client.raw_call(method, to_params(params)) {
+        let server = &SERVERS[order[(attempt - 1) % order.len()]];
+        trace!(%method, ?params, attempt, url = %server.cfg.url(), "rpc attempt");
+        match call_on(server, method, params) {
             Ok(v) => {
                 debug!(%method, "rpc success");
-                POOL.blocking_lock().push(client);
                 return Ok(v);
             }
             Err(e) => {
-                error!(%method, attempt, ?e, "rpc failed");
-                if attempt < 3 {
+                error!(%method, attempt, error = %e, "rpc failed");
+                if attempt < 3 && order.len() == 1 {
                     thread::sleep(Duration::from_millis(500 * attempt as u64));
                 }
             }
@@ -94,23 +329,83 @@ fn rpc_sync(method: &str, params: &[Value]) -> Result<Value> {
 
 pub async fn rpc_async(method: &str, params: &[Value]) -> Result<Value> {
     let m = method.to_owned();
-    let p: Vec<Value> = params.iter().cloned().collect();
+    let p: Vec<Value> = params.to_vec();
     tokio::task::spawn_blocking(move || rpc_sync(&m, &p))
         .await
         .map_err(|e| anyhow!("join error {e}"))?
 }
 
-pub fn fee_sat(vsize: u64) -> u64 {
-    let est = rpc_sync("blockchain.estimatefee", &[Value::from(6)])
+/// Dials a dedicated (unpooled) connection to the healthiest reachable server,
+/// for long-lived subscriptions.
+pub fn subscription_client() -> Result<(Client, String)> {
+    for i in ranked_servers() {
+        let server = &SERVERS[i];
+        match connect_once(&server.cfg) {
+            Ok(c) => return Ok((c, server.cfg.url())),
+            Err(e) => {
+                server.record_failure();
+                warn!(url = %server.cfg.url(), error = %e, "subscription dial failed");
+            }
+        }
+    }
+    bail!("no electrum server reachable for subscriptions")
+}
+
+/// Snapshot of every server's health, for the `/health` endpoint.
+pub fn server_status() -> Vec<Value> {
+    let now = Instant::now();
+    SERVERS
+        .iter()
+        .map(|s| {
+            let h = s.health.lock().unwrap();
+            json!({
+                "url": s.cfg.url(),
+                "latency_ms": h.latency_ms.round(),
+                "error_rate": h.error_rate,
+                "tip": h.tip,
+                "cooling_down": h.cooling(now),
+            })
+        })
+        .collect()
+}
+
+/// Periodically probes every server's tip so lagging or dead servers are
+/// demoted before the sweeper relies on them.
+pub async fn start_monitor() {
+    tokio::spawn(async move {
+        let mut iv = interval(CONFIG.probe_interval);
+        loop {
+            iv.tick().await;
+            let _ = tokio::task::spawn_blocking(|| {
+                for server in SERVERS.iter() {
+                    if server.health.lock().unwrap().cooling(Instant::now()) {
+                        continue;
+                    }
+                    if let Err(e) = call_on(server, "blockchain.headers.subscribe", &[]) {
+                        debug!(error = %e, "electrum probe failed");
+                    }
+                }
+            })
+            .await;
+        }
+    });
+}
+
+/// Server fee estimate for confirmation within `SWEEP_FEE_TARGET` blocks
+/// (default 6), in litoshis per vbyte; never below 1.
+pub fn fee_rate() -> u64 {
+    let target = env::var("SWEEP_FEE_TARGET")
+        .unwrap_or_else(|_| "6".into())
+        .parse::<u64>()
+        .unwrap_or(6);
+    let est = rpc_sync("blockchain.estimatefee", &[Value::from(target)])
         .unwrap_or(Value::from(0.0))
         .as_f64()
         .unwrap_or(0.0);
     let sat_per_vb = ((est * 1e8) / 1000.0).ceil() as u64;
-    vsize * sat_per_vb.max(1)
+    sat_per_vb.max(1)
 }
 
-pub async fn fee_sat_async(vsize: u64) -> u64 {
-    tokio::task::spawn_blocking(move || fee_sat(vsize))
-        .await
-        .unwrap_or(vsize)
+pub async fn fee_rate_async() -> u64 {
+    tokio::task::spawn_blocking(fee_rate).await.unwrap_or(1)
 }
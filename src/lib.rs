@@ -12,9 +12,18 @@ pub static MAIN_ADDRESS: Lazy<String> = Lazy::new(|| match std::env::var("MAIN_A
     }
 });
 
+pub mod auth;
+pub mod cli;
 pub mod db;
 pub mod electrum;
+pub mod live;
+pub mod migrations;
+pub mod psbt;
 pub mod routes;
 pub mod sweeper;
 pub mod utils;
-pub mod webhook;
\ No newline at end of file
+pub mod wallet;
+pub mod watcher;
+pub mod webhook;
+
+pub use webhook::{verify_signature, SignatureError, EVENT_ID_HEADER, SIGNATURE_HEADER};
@@ -0,0 +1,98 @@
+use crate::{db::PaymentStatus, utils::format_ltc};
+use once_cell::sync::Lazy;
+use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
+use tracing::debug;
+
+/// Updates buffered per subscriber; a subscriber further behind skips ahead.
+const CAPACITY: usize = 1024;
+
+static UPDATES: Lazy<Sender<PaymentUpdate>> = Lazy::new(|| broadcast::channel(CAPACITY).0);
+
+/// What the sweeper last observed of one payment. Fields it did not look at
+/// (e.g. on expiry, which is a DB-only pass) are `None`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaymentUpdate {
+    pub payment_id: String,
+    pub status: PaymentStatus,
+    /// litoshis, confirmed plus unconfirmed
+    pub received: Option<u64>,
+    pub confirmations: Option<u64>,
+}
+
+impl PaymentUpdate {
+    /// Only the status changed.
+    pub fn status(payment_id: &str, status: PaymentStatus) -> Self {
+        Self {
+            payment_id: payment_id.to_string(),
+            status,
+            received: None,
+            confirmations: None,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut body = json!({
+            "payment_id": self.payment_id,
+            "status": self.status,
+        });
+        if let Some(received) = self.received {
+            body["received"] = json!(format_ltc(received));
+            body["received_litoshis"] = json!(received);
+        }
+        if let Some(confirmations) = self.confirmations {
+            body["confirmations"] = json!(confirmations);
+        }
+        body
+    }
+
+    /// Nothing can change after this one.
+    pub fn is_final(&self) -> bool {
+        self.status == PaymentStatus::Swept
+    }
+}
+
+/// Hands an update to every open stream; a no-op without listeners.
+pub fn publish(update: PaymentUpdate) {
+    let _ = UPDATES.send(update);
+}
+
+/// Updates of one payment, without repeats.
+pub struct Subscription {
+    rx: Receiver<PaymentUpdate>,
+    payment_id: String,
+    last: Option<PaymentUpdate>,
+}
+
+impl Subscription {
+    /// Subscribe before reading the snapshot, so nothing falls in between.
+    pub fn new(payment_id: &str) -> Self {
+        Self {
+            rx: UPDATES.subscribe(),
+            payment_id: payment_id.to_string(),
+            last: None,
+        }
+    }
+
+    /// Records what the subscriber already has, so it is not sent again.
+    pub fn seen(&mut self, update: &PaymentUpdate) {
+        self.last = Some(update.clone());
+    }
+
+    /// Next update that differs from the last one seen.
+    pub async fn next(&mut self) -> Option<PaymentUpdate> {
+        loop {
+            match self.rx.recv().await {
+                Ok(u) if u.payment_id == self.payment_id && self.last.as_ref() != Some(&u) => {
+                    self.last = Some(u.clone());
+                    return Some(u);
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!(payment_id = %self.payment_id, skipped, "live stream lagged");
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
@@ -1,13 +1,20 @@
+mod auth;
+mod cli;
 mod db;
 mod electrum;
+mod live;
+mod migrations;
+mod psbt;
 mod routes;
 mod sweeper;
 mod utils;
+mod wallet;
+mod watcher;
 mod webhook;
-use actix_web::{App, HttpServer};
 use actix_cors::Cors;
+use actix_web::{middleware::from_fn, App, HttpServer};
 use dotenvy::dotenv;
-use std::env;
+use std::{env, sync::Arc};
 use tracing::{info, Level};
 use tracing_subscriber::FmtSubscriber;
 
@@ -43,23 +50,60 @@ async fn main() -> std::io::Result<()> {
     info!("Opening database: {}", db_file);
     let db = db::Db::open(&db_file).expect("Failed to open database");
 
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) != Some("migrate") {
+        migrations::on_startup(&db).expect("Database schema is not up to date");
+    }
+    if !args.is_empty() {
+        return cli::run(&args, &db).await.map_err(|e| {
+            eprintln!("{e:#}");
+            std::io::Error::other(e)
+        });
+    }
+
+    // fail fast on a missing or malformed WALLET_XPRV / WALLET_XPUB
+    info!(
+        watch_only = wallet::is_watch_only(),
+        "Wallet account loaded"
+    );
+
+    info!("Starting electrum health monitor");
+    electrum::start_monitor().await;
+
+    let policies = Arc::new(sweeper::Policies::from_env());
     info!("Starting sweeper");
-    sweeper::start(db.clone()).await;
+    sweeper::start(db.clone(), policies.clone()).await;
+
+    info!("Starting webhook delivery");
+    webhook::start(db.clone());
 
     info!("Starting HTTP server on 0.0.0.0:{}", port);
+    // comma-separated origins allowed to call the API from a browser; "*" allows any (default none)
+    let cors_origins = env::var("CORS_ORIGINS").unwrap_or_default();
     HttpServer::new(move || {
+        let mut cors = Cors::default()
+            .allow_any_method()
+            .allow_any_header()
+            .max_age(3600);
+        for origin in cors_origins
+            .split(',')
+            .map(str::trim)
+            .filter(|o| !o.is_empty())
+        {
+            cors = if origin == "*" {
+                cors.allow_any_origin()
+            } else {
+                cors.allowed_origin(origin)
+            };
+        }
         App::new()
-            .wrap(
-                Cors::default()
-                    .allow_any_origin()
-                    .allow_any_method()
-                    .allow_any_header()
-                    .max_age(3600)
-            )
+            .wrap(from_fn(auth::middleware))
+            .wrap(cors)
             .app_data(actix_web::web::Data::new(db.clone()))
+            .app_data(actix_web::web::Data::from(policies.clone()))
             .configure(routes::config)
     })
     .bind(("0.0.0.0", port))?
     .run()
     .await
-}
\ No newline at end of file
+}
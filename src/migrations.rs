@@ -0,0 +1,461 @@
+use crate::db::Db;
+use anyhow::{bail, Result};
+use rusqlite::{Connection, OptionalExtension, Result as SqliteResult, Transaction};
+use std::env;
+use tracing::info;
+
+/// One ordered schema change. `up` runs in a transaction together with the
+/// `schema_version` row recording it, so a migration applies fully or not at all.
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    up: fn(&Transaction) -> SqliteResult<()>,
+}
+
+/// Every schema change, oldest first. Never edit a released entry; append a
+/// new one instead.
+///
+/// Versions 1-9 replay the changes made before the database was versioned and
+/// are idempotent, so databases created by older binaries (version 0, whatever
+/// subset of them they already have) upgrade cleanly.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create payments",
+        up: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE IF NOT EXISTS payments(
+                    id TEXT PRIMARY KEY,
+                    address TEXT UNIQUE,
+                    wif_enc TEXT NOT NULL,
+                    amount REAL,
+                    status TEXT,
+                    created_at INTEGER,
+                    updated_at INTEGER,
+                    expires_at INTEGER
+                 );
+                 CREATE INDEX IF NOT EXISTS idx_payments_expires_at ON payments(expires_at);",
+            )
+        },
+    },
+    Migration {
+        version: 2,
+        name: "index payment status",
+        up: |tx| {
+            tx.execute_batch("CREATE INDEX IF NOT EXISTS idx_payments_status ON payments(status)")
+        },
+    },
+    Migration {
+        version: 3,
+        name: "hd derivation index",
+        up: |tx| {
+            add_column_if_missing(tx, "payments", "derivation_index", "INTEGER")?;
+            tx.execute_batch(
+                "CREATE UNIQUE INDEX IF NOT EXISTS idx_payments_derivation_index
+                     ON payments(derivation_index);
+                 CREATE TABLE IF NOT EXISTS counters(
+                     name TEXT PRIMARY KEY,
+                     value INTEGER NOT NULL
+                 );",
+            )
+        },
+    },
+    Migration {
+        version: 4,
+        name: "create psbts",
+        up: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE IF NOT EXISTS psbts(
+                     id TEXT PRIMARY KEY,
+                     payment_ids TEXT NOT NULL,
+                     psbt TEXT NOT NULL,
+                     status TEXT NOT NULL,
+                     txid TEXT,
+                     created_at INTEGER,
+                     updated_at INTEGER
+                 );
+                 CREATE INDEX IF NOT EXISTS idx_psbts_status ON psbts(status);",
+            )
+        },
+    },
+    Migration {
+        version: 5,
+        name: "payment sweep txid",
+        up: |tx| add_column_if_missing(tx, "payments", "sweep_txid", "TEXT"),
+    },
+    Migration {
+        version: 6,
+        name: "create sweeps",
+        up: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE IF NOT EXISTS sweeps(
+                     id TEXT PRIMARY KEY,
+                     txid TEXT NOT NULL UNIQUE,
+                     raw_hex TEXT NOT NULL,
+                     fee INTEGER NOT NULL,
+                     inputs TEXT NOT NULL,
+                     payment_ids TEXT NOT NULL,
+                     status TEXT NOT NULL,
+                     broadcast_attempts INTEGER NOT NULL DEFAULT 1,
+                     last_broadcast_at INTEGER,
+                     confirmed_height INTEGER,
+                     created_at INTEGER,
+                     updated_at INTEGER
+                 );
+                 CREATE INDEX IF NOT EXISTS idx_sweeps_status ON sweeps(status);
+                 UPDATE payments SET status='swept' WHERE status='completed';",
+            )
+        },
+    },
+    Migration {
+        version: 7,
+        name: "sweep fee bumps",
+        up: |tx| {
+            add_column_if_missing(tx, "sweeps", "fee_rate", "INTEGER NOT NULL DEFAULT 0")?;
+            add_column_if_missing(
+                tx,
+                "sweeps",
+                "broadcast_height",
+                "INTEGER NOT NULL DEFAULT 0",
+            )?;
+            add_column_if_missing(tx, "sweeps", "fee_bumps", "TEXT NOT NULL DEFAULT '[]'")
+        },
+    },
+    Migration {
+        version: 8,
+        name: "typed payment status",
+        up: |tx| {
+            tx.execute_batch(
+                "UPDATE payments SET status='created' WHERE status='pending';
+                 UPDATE payments SET status='paid' WHERE status='sweeping';",
+            )
+        },
+    },
+    Migration {
+        version: 9,
+        name: "integer payment amounts",
+        up: |tx| {
+            add_column_if_missing(
+                tx,
+                "payments",
+                "amount_litoshis",
+                "INTEGER NOT NULL DEFAULT 0",
+            )?;
+            // legacy REAL amounts are rounded, not truncated, to litoshis and cleared
+            tx.execute_batch(
+                "UPDATE payments
+                 SET amount_litoshis=CAST(ROUND(amount*100000000) AS INTEGER),
+                     amount=NULL
+                 WHERE amount IS NOT NULL",
+            )
+        },
+    },
+    Migration {
+        version: 10,
+        name: "payment listing indexes",
+        up: |tx| {
+            tx.execute_batch(
+                "CREATE INDEX idx_payments_created_at_id ON payments(created_at, id);
+                 CREATE INDEX idx_payments_updated_at_id ON payments(updated_at, id);
+                 CREATE INDEX idx_payments_amount_id ON payments(amount_litoshis, id);",
+            )
+        },
+    },
+    Migration {
+        version: 11,
+        name: "api keys and payment read tokens",
+        up: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE api_keys(
+                     id TEXT PRIMARY KEY,
+                     name TEXT NOT NULL,
+                     key_hash TEXT NOT NULL UNIQUE,
+                     scopes TEXT NOT NULL,
+                     created_at INTEGER NOT NULL,
+                     revoked_at INTEGER
+                 );
+                 ALTER TABLE payments ADD COLUMN read_token_hash TEXT;",
+            )
+        },
+    },
+    Migration {
+        version: 12,
+        name: "idempotency keys",
+        up: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE idempotency_keys(
+                     owner TEXT NOT NULL,
+                     key TEXT NOT NULL,
+                     request_hash TEXT NOT NULL,
+                     payment_id TEXT,
+                     response TEXT,
+                     created_at INTEGER NOT NULL,
+                     PRIMARY KEY(owner, key)
+                 );
+                 CREATE INDEX idx_idempotency_keys_created_at ON idempotency_keys(created_at);",
+            )
+        },
+    },
+    Migration {
+        version: 13,
+        name: "payment order reference and metadata",
+        up: |tx| {
+            tx.execute_batch(
+                "ALTER TABLE payments ADD COLUMN order_id TEXT;
+                 ALTER TABLE payments ADD COLUMN description TEXT;
+                 ALTER TABLE payments ADD COLUMN metadata TEXT NOT NULL DEFAULT '{}';
+                 CREATE INDEX idx_payments_order_id ON payments(order_id);",
+            )
+        },
+    },
+    Migration {
+        version: 14,
+        name: "webhook outbox",
+        up: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE webhook_events(
+                     id TEXT PRIMARY KEY,
+                     event TEXT NOT NULL,
+                     payment_id TEXT NOT NULL,
+                     payload TEXT NOT NULL,
+                     status TEXT NOT NULL,
+                     attempts INTEGER NOT NULL DEFAULT 0,
+                     next_attempt_at INTEGER NOT NULL,
+                     last_error TEXT,
+                     created_at INTEGER NOT NULL,
+                     updated_at INTEGER NOT NULL,
+                     delivered_at INTEGER
+                 );
+                 CREATE INDEX idx_webhook_events_due ON webhook_events(status, next_attempt_at);
+                 CREATE INDEX idx_webhook_events_payment_id ON webhook_events(payment_id);
+                 CREATE TABLE webhook_attempts(
+                     id INTEGER PRIMARY KEY AUTOINCREMENT,
+                     event_id TEXT NOT NULL,
+                     attempted_at INTEGER NOT NULL,
+                     status_code INTEGER,
+                     latency_ms INTEGER NOT NULL,
+                     response TEXT,
+                     error TEXT
+                 );
+                 CREATE INDEX idx_webhook_attempts_event_id ON webhook_attempts(event_id);",
+            )
+        },
+    },
+    Migration {
+        version: 15,
+        name: "webhook delivery log index",
+        up: |tx| {
+            tx.execute_batch(
+                "CREATE INDEX idx_webhook_events_created_at_id ON webhook_events(created_at, id)",
+            )
+        },
+    },
+    Migration {
+        version: 16,
+        name: "merchants",
+        up: |tx| {
+            // existing payments, keys and events belong to the `default` merchant,
+            // which keeps using the process-wide configuration
+            tx.execute_batch(
+                "CREATE TABLE merchants(
+                     id TEXT PRIMARY KEY,
+                     name TEXT NOT NULL,
+                     payout_address TEXT,
+                     payout_xpub TEXT,
+                     payout_index INTEGER NOT NULL DEFAULT 0,
+                     webhook_url TEXT,
+                     webhook_secret TEXT,
+                     confirmations INTEGER,
+                     default_ttl INTEGER,
+                     created_at INTEGER NOT NULL,
+                     updated_at INTEGER NOT NULL
+                 );
+                 INSERT INTO merchants(id,name,created_at,updated_at)
+                     VALUES('default','default',strftime('%s','now'),strftime('%s','now'));
+                 ALTER TABLE payments ADD COLUMN merchant_id TEXT NOT NULL DEFAULT 'default';
+                 CREATE INDEX idx_payments_merchant_id ON payments(merchant_id, created_at, id);
+                 ALTER TABLE api_keys ADD COLUMN merchant_id TEXT NOT NULL DEFAULT 'default';
+                 ALTER TABLE webhook_events ADD COLUMN merchant_id TEXT NOT NULL DEFAULT 'default';
+                 CREATE INDEX idx_webhook_events_merchant_id
+                     ON webhook_events(merchant_id, created_at, id);",
+            )
+        },
+    },
+    Migration {
+        version: 17,
+        name: "payment chain state",
+        up: |tx| {
+            // filled in by the next sync of each payment; NULL last_synced_at
+            // means it has not been looked at since
+            tx.execute_batch(
+                "ALTER TABLE payments ADD COLUMN received_litoshis INTEGER NOT NULL DEFAULT 0;
+                 ALTER TABLE payments ADD COLUMN confirmed_litoshis INTEGER NOT NULL DEFAULT 0;
+                 ALTER TABLE payments ADD COLUMN first_seen_txid TEXT;
+                 ALTER TABLE payments ADD COLUMN first_seen_at INTEGER;
+                 ALTER TABLE payments ADD COLUMN confirmed_height INTEGER;
+                 ALTER TABLE payments ADD COLUMN last_synced_at INTEGER;",
+            )
+        },
+    },
+    Migration {
+        version: 18,
+        name: "payment transactions",
+        up: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE payment_txs(
+                     payment_id TEXT NOT NULL,
+                     txid TEXT NOT NULL,
+                     vout INTEGER NOT NULL,
+                     value INTEGER NOT NULL,
+                     height INTEGER,
+                     first_seen_at INTEGER NOT NULL,
+                     confirmed_at INTEGER,
+                     double_spent INTEGER NOT NULL DEFAULT 0,
+                     PRIMARY KEY(payment_id, txid, vout)
+                 );",
+            )
+        },
+    },
+    Migration {
+        version: 19,
+        name: "payment amount delta",
+        up: |tx| tx.execute_batch("ALTER TABLE payments ADD COLUMN amount_delta INTEGER;"),
+    },
+    Migration {
+        version: 20,
+        name: "late payments",
+        up: |tx| {
+            tx.execute_batch(
+                "ALTER TABLE payments ADD COLUMN late_action TEXT;
+                 ALTER TABLE payments ADD COLUMN refund_address TEXT;",
+            )
+        },
+    },
+    Migration {
+        version: 21,
+        name: "idempotency key claims",
+        up: |tx| {
+            // stored responses carried plaintext read tokens; their replays go without one
+            tx.execute_batch(
+                "ALTER TABLE idempotency_keys ADD COLUMN claimed_at INTEGER;
+                 ALTER TABLE idempotency_keys ADD COLUMN completed_at INTEGER;
+                 UPDATE idempotency_keys
+                 SET claimed_at=created_at,
+                     completed_at=CASE WHEN response IS NOT NULL THEN created_at END,
+                     response=NULL;",
+            )
+        },
+    },
+    Migration {
+        version: 22,
+        name: "psbt replacements",
+        up: |tx| add_column_if_missing(tx, "psbts", "replaces_sweep", "TEXT"),
+    },
+    Migration {
+        version: 23,
+        name: "payment tx block time",
+        up: |tx| add_column_if_missing(tx, "payment_txs", "block_time", "INTEGER"),
+    },
+];
+
+/// Schema version this binary expects.
+pub fn latest() -> u32 {
+    MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// Adds `column` to `table` on databases created before it existed.
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> SqliteResult<()> {
+    let exists = conn
+        .prepare(&format!("PRAGMA table_info({table})"))?
+        .query_map([], |r| r.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?
+        .iter()
+        .any(|c| c == column);
+    if !exists {
+        conn.execute_batch(&format!("ALTER TABLE {table} ADD COLUMN {column} {decl}"))?;
+    }
+    Ok(())
+}
+
+/// Highest applied migration; 0 for a new or pre-versioning database.
+pub fn current_version(conn: &Connection) -> SqliteResult<u32> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_version(
+             version INTEGER PRIMARY KEY,
+             name TEXT NOT NULL,
+             applied_at INTEGER NOT NULL
+         )",
+    )?;
+    let version: Option<u32> = conn
+        .query_row("SELECT MAX(version) FROM schema_version", [], |r| r.get(0))
+        .optional()?
+        .flatten();
+    Ok(version.unwrap_or(0))
+}
+
+/// Refuses a database migrated by a newer binary: its schema may have changed
+/// in ways this one does not understand.
+pub fn check_compatible(conn: &Connection) -> Result<u32> {
+    let version = current_version(conn)?;
+    if version > latest() {
+        bail!(
+            "database schema version {version} is newer than this binary supports ({}); \
+             upgrade litegate",
+            latest()
+        );
+    }
+    Ok(version)
+}
+
+/// Migrations not yet applied, in order.
+pub fn pending(conn: &Connection) -> Result<Vec<&'static Migration>> {
+    let version = check_compatible(conn)?;
+    Ok(MIGRATIONS.iter().filter(|m| m.version > version).collect())
+}
+
+/// Applies every pending migration, each in its own transaction, and returns
+/// the ones applied.
+pub fn migrate(conn: &mut Connection) -> Result<Vec<&'static Migration>> {
+    let todo = pending(conn)?;
+    for m in &todo {
+        let tx = conn.transaction()?;
+        (m.up)(&tx)?;
+        tx.execute(
+            "INSERT INTO schema_version(version,name,applied_at)
+             VALUES(?,?,strftime('%s','now'))",
+            rusqlite::params![m.version, m.name],
+        )?;
+        tx.commit()?;
+        info!(version = m.version, name = m.name, "applied migration");
+    }
+    Ok(todo)
+}
+
+/// Brings the schema up to date before the server or a CLI command touches
+/// data. With `DB_AUTO_MIGRATE=false` pending migrations are an error instead,
+/// to be applied explicitly with `litegate migrate`.
+pub fn on_startup(db: &Db) -> Result<()> {
+    let auto = env::var("DB_AUTO_MIGRATE")
+        .unwrap_or_else(|_| "true".into())
+        .parse::<bool>()
+        .unwrap_or(true);
+    let mut conn = db.0.lock().unwrap();
+    if auto {
+        migrate(&mut conn)?;
+        return Ok(());
+    }
+    let todo = pending(&conn)?;
+    if let Some(first) = todo.first() {
+        bail!(
+            "database schema is at version {}, this binary needs {}; run `litegate migrate`",
+            first.version - 1,
+            latest()
+        );
+    }
+    Ok(())
+}
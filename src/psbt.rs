@@ -0,0 +1,192 @@
+use crate::{
+    db::{Db, Payment, PsbtRecord, SweepInput},
+    electrum::rpc_async,
+    sweeper::{broadcast_replacement, broadcast_sweep},
+    utils::{script_hash, script_pubkey},
+    wallet::{account_origin, account_xpub, derive_pubkey, key_source},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use bitcoin::{
+    secp256k1::PublicKey, util::psbt::PartiallySignedTransaction as Psbt, Transaction, TxOut,
+    Witness,
+};
+use std::{collections::HashSet, str::FromStr};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+/// Stores an unsigned sweep as a PSBT for offline signing. `inputs` gives the
+/// payment and value spent by each transaction input, in order. Each input
+/// carries its witness UTXO and BIP32 origin so any BIP174 signer holding the
+/// account key can sign it. With `replaces_sweep` it is a fee bump of that
+/// broadcast sweep rather than a new one.
+pub fn export(
+    db: &Db,
+    tx: Transaction,
+    inputs: &[(&Payment, u64)],
+    replaces_sweep: Option<&str>,
+) -> Result<PsbtRecord> {
+    let mut psbt = Psbt::from_unsigned_tx(tx)?;
+    let mut payment_ids: Vec<String> = Vec::new();
+    for (input, (p, value)) in psbt.inputs.iter_mut().zip(inputs) {
+        let index = p
+            .derivation_index
+            .ok_or_else(|| anyhow!("payment {} has no derivation index", p.id))?;
+        let pk = PublicKey::from_slice(&derive_pubkey(index)?)?;
+        input.witness_utxo = Some(TxOut {
+            value: *value,
+            script_pubkey: script_pubkey(&p.address),
+        });
+        input.bip32_derivation.insert(pk, key_source(index)?);
+        if !payment_ids.contains(&p.id) {
+            payment_ids.push(p.id.clone());
+        }
+    }
+    psbt.xpub.insert(account_xpub(), account_origin());
+
+    let record = PsbtRecord {
+        id: Uuid::new_v4().to_string(),
+        payment_ids,
+        psbt: psbt.to_string(),
+        status: "unsigned".into(),
+        txid: None,
+        replaces_sweep: replaces_sweep.map(str::to_string),
+        created_at: 0,
+        updated_at: 0,
+    };
+    db.insert_psbt(&record)?;
+    info!(psbt_id = %record.id, payment_ids = ?record.payment_ids,
+        replaces_sweep = ?record.replaces_sweep, "exported unsigned sweep");
+    Ok(record)
+}
+
+/// Turns single-key P2WPKH partial signatures into final witnesses and
+/// extracts the network transaction.
+fn finalize(mut psbt: Psbt) -> Result<Transaction> {
+    for (i, input) in psbt.inputs.iter_mut().enumerate() {
+        if input.final_script_witness.is_some() {
+            continue;
+        }
+        let (pk, sig) = input
+            .partial_sigs
+            .iter()
+            .next()
+            .ok_or_else(|| anyhow!("input {i} is not signed"))?;
+        let mut witness = Witness::default();
+        witness.push(sig.to_vec());
+        witness.push(pk.to_bytes());
+        input.final_script_witness = Some(witness);
+    }
+    Ok(psbt.extract_tx())
+}
+
+/// Broadcasts a signed copy of exported PSBT `id`; its payments then follow
+/// the normal sweep tracking. A fee bump takes the place of the sweep it
+/// replaces. Returns the txid.
+pub async fn submit(db: &Db, id: &str, signed: &str) -> Result<String> {
+    let record = db
+        .find_psbt(id)?
+        .ok_or_else(|| anyhow!("psbt {id} not found"))?;
+    if record.status != "unsigned" {
+        bail!("psbt {id} is already {}", record.status);
+    }
+    let exported = Psbt::from_str(&record.psbt).context("stored psbt")?;
+    let signed = Psbt::from_str(signed.trim()).context("signed psbt is not valid base64 PSBT")?;
+    if signed.unsigned_tx.txid() != exported.unsigned_tx.txid() {
+        bail!("signed psbt does not match the exported transaction");
+    }
+
+    // map each input back to the payment whose address it spends from
+    let mut inputs = Vec::with_capacity(signed.inputs.len());
+    let mut payments = Vec::new();
+    for id in &record.payment_ids {
+        payments.extend(db.find(id)?);
+    }
+    let mut spent = 0u64;
+    for (txin, input) in signed.unsigned_tx.input.iter().zip(&signed.inputs) {
+        let utxo = input
+            .witness_utxo
+            .as_ref()
+            .ok_or_else(|| anyhow!("signed psbt input is missing its witness utxo"))?;
+        let p = payments
+            .iter()
+            .find(|p| script_pubkey(&p.address) == utxo.script_pubkey)
+            .ok_or_else(|| anyhow!("signed psbt spends an input of no covered payment"))?;
+        spent += utxo.value;
+        inputs.push(SweepInput {
+            txid: txin.previous_output.txid.to_string(),
+            vout: txin.previous_output.vout,
+            value: utxo.value,
+            payment_id: p.id.clone(),
+            address: p.address.clone(),
+        });
+    }
+    let sent: u64 = signed.unsigned_tx.output.iter().map(|o| o.value).sum();
+
+    let tx = finalize(signed)?;
+    let fee = spent.saturating_sub(sent);
+    let fee_rate = fee / (tx.vsize() as u64).max(1);
+    let txid = match &record.replaces_sweep {
+        Some(sweep_id) => broadcast_replacement(db, sweep_id, &tx, fee, fee_rate).await?,
+        None => broadcast_sweep(db, &tx, fee, fee_rate, inputs).await?,
+    };
+    info!(psbt_id = %id, %txid, "broadcast externally signed sweep");
+
+    db.mark_psbt_broadcast(id, &txid)?;
+    Ok(txid)
+}
+
+/// Discards unsigned PSBT `id`, e.g. one that will never be signed. Its
+/// payments are swept again from their current UTXOs.
+pub fn cancel(db: &Db, id: &str) -> Result<()> {
+    let record = db
+        .find_psbt(id)?
+        .ok_or_else(|| anyhow!("psbt {id} not found"))?;
+    if !db.discard_psbt(id, "cancelled")? {
+        bail!("psbt {id} is already {}", record.status);
+    }
+    info!(psbt_id = %id, payment_ids = ?record.payment_ids, "cancelled unsigned sweep");
+    Ok(())
+}
+
+/// Marks `stale` every unsigned PSBT spending an output that is no longer
+/// unspent, which could never be broadcast, so its payments are rebuilt
+/// from what their addresses hold now. A fee bump is stale once the sweep it
+/// replaces is no longer pending. Returns the ids discarded.
+pub async fn discard_stale(db: &Db) -> Result<Vec<String>> {
+    let mut stale = Vec::new();
+    let pending: HashSet<String> = db.active_sweeps()?.into_iter().map(|s| s.id).collect();
+    for record in db.unsigned_psbts()? {
+        if let Some(sweep_id) = &record.replaces_sweep {
+            // its inputs are spent, by the sweep it replaces
+            if !pending.contains(sweep_id) && db.discard_psbt(&record.id, "stale")? {
+                info!(psbt_id = %record.id, %sweep_id, "fee bump of a settled sweep discarded");
+                stale.push(record.id);
+            }
+            continue;
+        }
+        let psbt = Psbt::from_str(&record.psbt).context("stored psbt")?;
+        let mut unspent = HashSet::new();
+        for id in &record.payment_ids {
+            let Some(p) = db.find(id)? else { continue };
+            let utxos = rpc_async(
+                "blockchain.scripthash.listunspent",
+                &[script_hash(&p.address).into()],
+            )
+            .await?;
+            for u in utxos.as_array().into_iter().flatten() {
+                if let (Some(txid), Some(vout)) = (u["tx_hash"].as_str(), u["tx_pos"].as_u64()) {
+                    unspent.insert((txid.to_string(), vout as u32));
+                }
+            }
+        }
+        let gone = psbt.unsigned_tx.input.iter().any(|i| {
+            !unspent.contains(&(i.previous_output.txid.to_string(), i.previous_output.vout))
+        });
+        if gone && db.discard_psbt(&record.id, "stale")? {
+            warn!(psbt_id = %record.id, payment_ids = ?record.payment_ids,
+                "unsigned sweep spends outputs that are gone, discarded");
+            stale.push(record.id);
+        }
+    }
+    Ok(stale)
+}
@@ -1,128 +1,1043 @@
 use crate::{
-    db::{Db, Payment},
-    electrum::rpc_async,
-    utils::{encrypt_wif, new_key, script_hash},
+    auth::{generate_payment_token, hash_token, Caller},
+    db::{
+        Db, IdempotencyClaim, LateAction, OutboxEvent, Payment, PaymentFilter, PaymentPage,
+        PaymentSort, PaymentStatus, PaymentTx, WebhookEventFilter, DEFAULT_MERCHANT,
+    },
+    electrum::server_status,
+    live::{self, PaymentUpdate, Subscription},
+    psbt,
+    sweeper::Policies,
+    utils::{
+        decrypt_secret, deserialize_ltc, encrypt_secret, format_ltc, format_ltc_signed,
+        is_segwit_v0_address, parse_ltc,
+    },
+    wallet::derive_address,
 };
-use actix_web::{web, HttpResponse};
-use serde::Deserialize;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use actix_ws::{CloseCode, Message};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::task::spawn_blocking;
+use sha2::{Digest, Sha256};
+use std::{
+    convert::Infallible,
+    env,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    select,
+    task::spawn_blocking,
+    time::{interval_at, sleep, timeout, Duration, Instant},
+};
 use uuid::Uuid;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 struct PayReq {
-    amount: f64,
-    ttl: u64,
+    /// decimal LTC string, e.g. "0.29"
+    #[serde(deserialize_with = "deserialize_ltc")]
+    amount: u64,
+    /// seconds until expiry, 0 = never; defaults to the merchant's `default_ttl`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl: Option<u64>,
+    /// merchant reference, e.g. their order number
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    order_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    /// any JSON object, stored and echoed back as is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+const ORDER_ID_MAX_LEN: usize = 255;
+const DESCRIPTION_MAX_LEN: usize = 1000;
+const METADATA_MAX_BYTES: usize = 8192;
+
+impl PayReq {
+    fn validate(&self) -> Result<(), String> {
+        if self.amount == 0 {
+            return Err("amount must be positive".into());
+        }
+        match &self.order_id {
+            Some(o) if o.is_empty() || o.len() > ORDER_ID_MAX_LEN => {
+                return Err(format!("order_id must be 1-{ORDER_ID_MAX_LEN} bytes"))
+            }
+            _ => {}
+        }
+        if self
+            .description
+            .as_ref()
+            .is_some_and(|d| d.len() > DESCRIPTION_MAX_LEN)
+        {
+            return Err(format!(
+                "description must be at most {DESCRIPTION_MAX_LEN} bytes"
+            ));
+        }
+        if let Some(m) = &self.metadata {
+            if serde_json::to_string(m).unwrap().len() > METADATA_MAX_BYTES {
+                return Err(format!(
+                    "metadata must be at most {METADATA_MAX_BYTES} bytes of JSON"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Query of `GET /payments`. `status` is comma-separated, amounts are decimal
+/// LTC strings, times Unix seconds.
+#[derive(Deserialize, Debug)]
+struct ListReq {
+    status: Option<String>,
+    created_after: Option<i64>,
+    created_before: Option<i64>,
+    updated_after: Option<i64>,
+    updated_before: Option<i64>,
+    min_amount: Option<String>,
+    max_amount: Option<String>,
+    address: Option<String>,
+    order_id: Option<String>,
+    /// substring of order_id or description
+    q: Option<String>,
+    /// `key:value`, matched against a top-level metadata key
+    metadata: Option<String>,
+    /// only honoured for admin keys; others always see their own merchant
+    merchant_id: Option<String>,
+    /// created_at (default) / updated_at / amount
+    sort: Option<String>,
+    /// desc (default) / asc
+    order: Option<String>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+const LIST_DEFAULT_LIMIT: usize = 50;
+const LIST_MAX_LIMIT: usize = 200;
+
+/// Query of `GET /webhooks/deliveries`. `status` is comma-separated.
+#[derive(Deserialize, Debug)]
+struct DeliveryListReq {
+    /// only honoured for admin keys, as in `ListReq`
+    merchant_id: Option<String>,
+    payment_id: Option<String>,
+    /// pending / delivered / dead
+    status: Option<String>,
+    created_after: Option<i64>,
+    created_before: Option<i64>,
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+/// Body of `POST /webhooks/deliveries/replay`: events created in `[since, until)`.
+#[derive(Deserialize, Debug)]
+struct ReplayReq {
+    since: i64,
+    until: Option<i64>,
+}
+
+const DELIVERY_STATUSES: [&str; 3] = ["pending", "delivered", "dead"];
+
+#[derive(Deserialize, Debug)]
+struct SubmitPsbtReq {
+    psbt: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct ResolveLateReq {
+    action: LateAction,
+    /// required with `refund`
+    refund_address: Option<String>,
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(web::resource("/health").route(web::get().to(health_check)))
-        .service(web::resource("/payments").route(web::post().to(create_payment)))
-        .service(web::resource("/payments/{id}").route(web::get().to(get_payment)));
+        .service(
+            web::resource("/payments")
+                .route(web::post().to(create_payment))
+                .route(web::get().to(list_payments)),
+        )
+        .service(web::resource("/payments/{id}").route(web::get().to(get_payment)))
+        .service(web::resource("/payments/{id}/events").route(web::get().to(payment_events)))
+        .service(web::resource("/payments/{id}/ws").route(web::get().to(payment_ws)))
+        .service(web::resource("/payments/{id}/late").route(web::post().to(resolve_late)))
+        .service(web::resource("/psbts").route(web::get().to(list_psbts)))
+        .service(web::resource("/psbts/{id}").route(web::get().to(get_psbt)))
+        .service(web::resource("/psbts/{id}/submit").route(web::post().to(submit_psbt)))
+        .service(web::resource("/psbts/{id}/cancel").route(web::post().to(cancel_psbt)))
+        .service(web::resource("/webhooks/deliveries").route(web::get().to(list_deliveries)))
+        .service(
+            web::resource("/webhooks/deliveries/replay").route(web::post().to(replay_deliveries)),
+        )
+        .service(web::resource("/webhooks/deliveries/{id}").route(web::get().to(get_delivery)))
+        .service(
+            web::resource("/webhooks/deliveries/{id}/replay")
+                .route(web::post().to(replay_delivery)),
+        );
 }
 
 async fn health_check() -> HttpResponse {
     HttpResponse::Ok().json(json!({
         "status": "ok",
-        "timestamp": SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+        "timestamp": SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+        "electrum": server_status(),
     }))
 }
 
-async fn create_payment(db: web::Data<Db>, req: web::Json<PayReq>) -> HttpResponse {
-    if req.amount <= 0.0 {
-        return HttpResponse::BadRequest().finish();
+const IDEMPOTENCY_KEY_MAX_LEN: usize = 255;
+
+/// Seconds after which an unfinished idempotent request is presumed dead and
+/// a retry takes its key over.
+const IDEMPOTENCY_CLAIM_TIMEOUT: i64 = 60;
+
+/// `POST /payments`. With an `Idempotency-Key` header a retry carrying the same
+/// key and body gets the original response back instead of a second invoice;
+/// the same key with a different body, or while the first request is still
+/// running, is a 409. Keys are scoped to the API key and kept for
+/// `IDEMPOTENCY_KEY_TTL` seconds.
+async fn create_payment(
+    db: web::Data<Db>,
+    http: HttpRequest,
+    req: web::Json<PayReq>,
+) -> HttpResponse {
+    if let Err(e) = req.validate() {
+        return HttpResponse::BadRequest().json(json!({ "error": e }));
     }
-    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
-    let expires_at = if req.ttl == 0 { 0 } else { now + req.ttl as i64 };
-    let id = Uuid::new_v4().to_string();
-    let (_, wif, addr) = new_key();
-    let wif_enc = encrypt_wif(&wif);
+    let key = http
+        .headers()
+        .get("Idempotency-Key")
+        .map(|v| v.to_str().map(str::to_string));
+    let key = match key {
+        None => None,
+        Some(Ok(k)) if !k.is_empty() && k.len() <= IDEMPOTENCY_KEY_MAX_LEN => Some(k),
+        Some(_) => {
+            return HttpResponse::BadRequest().json(json!({ "error": "invalid Idempotency-Key" }))
+        }
+    };
+    let merchant_id = match http.extensions().get::<Caller>() {
+        Some(Caller::Key { merchant_id, .. }) => merchant_id.clone(),
+        _ => DEFAULT_MERCHANT.to_string(),
+    };
+    let db_clone = db.clone();
+    let m = merchant_id.clone();
+    let merchant = match spawn_blocking(move || db_clone.find_merchant(&m))
+        .await
+        .unwrap()
+    {
+        Ok(Some(merchant)) => merchant,
+        _ => return HttpResponse::InternalServerError().finish(),
+    };
+    let Some(ttl) = req.ttl.or(merchant.default_ttl) else {
+        return HttpResponse::BadRequest().json(json!({ "error": "ttl is required" }));
+    };
+
+    let Some(key) = key else {
+        let id = Uuid::new_v4().to_string();
+        return match issue_payment(&db, &req, &merchant_id, ttl, id).await {
+            Some(body) => HttpResponse::Ok().json(body),
+            None => HttpResponse::InternalServerError().finish(),
+        };
+    };
+
+    let owner = match http.extensions().get::<Caller>() {
+        Some(Caller::Key { id, .. }) => id.clone(),
+        _ => String::new(),
+    };
+    let request_hash = hex::encode(Sha256::digest(serde_json::to_string(&*req).unwrap()));
+    let key_ttl = env::var("IDEMPOTENCY_KEY_TTL")
+        .unwrap_or_else(|_| "86400".into())
+        .parse::<i64>()
+        .unwrap_or(86400);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let (db_clone, o, k) = (db.clone(), owner.clone(), key.clone());
+    let claim = spawn_blocking(move || {
+        db_clone.claim_idempotency_key(
+            &o,
+            &k,
+            &request_hash,
+            &Uuid::new_v4().to_string(),
+            now - key_ttl,
+            now - IDEMPOTENCY_CLAIM_TIMEOUT,
+        )
+    })
+    .await
+    .unwrap();
+    let payment_id = match claim {
+        Ok(IdempotencyClaim::New(id)) => id,
+        Ok(IdempotencyClaim::Replay(id, token)) => {
+            return match replay_payment(&db, &id, token).await {
+                Some(body) => HttpResponse::Ok()
+                    .insert_header(("Idempotent-Replayed", "true"))
+                    .json(body),
+                None => HttpResponse::InternalServerError().finish(),
+            }
+        }
+        Ok(IdempotencyClaim::InProgress) => {
+            return HttpResponse::Conflict().json(
+                json!({ "error": "a request with this Idempotency-Key is still in progress" }),
+            )
+        }
+        Ok(IdempotencyClaim::Mismatch) => {
+            return HttpResponse::Conflict().json(
+                json!({ "error": "Idempotency-Key was already used with a different request" }),
+            )
+        }
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    // a taken-over claim may have got as far as storing its payment
+    let body = match reissue_payment(&db, &payment_id).await {
+        Some(body) => Some(body),
+        None => issue_payment(&db, &req, &merchant_id, ttl, payment_id).await,
+    };
+    let db_clone = db.clone();
+    let issued = body.is_some();
+    let token_enc = body
+        .as_ref()
+        .and_then(|b| b["read_token"].as_str())
+        .and_then(encrypt_secret);
+    let saved = spawn_blocking(move || {
+        if issued {
+            db_clone.complete_idempotency_key(&owner, &key, token_enc.as_deref())
+        } else {
+            db_clone.release_idempotency_key(&owner, &key)
+        }
+    })
+    .await
+    .unwrap();
+    match (body, saved) {
+        (Some(body), Ok(())) => HttpResponse::Ok().json(body),
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// Derives a fresh address and stores payment `id` for `merchant_id`;
+/// returns the response body.
+async fn issue_payment(
+    db: &web::Data<Db>,
+    req: &PayReq,
+    merchant_id: &str,
+    ttl: u64,
+    id: String,
+) -> Option<serde_json::Value> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let expires_at = if ttl == 0 { 0 } else { now + ttl as i64 };
+    let db_clone = db.clone();
+    let index = spawn_blocking(move || db_clone.next_derivation_index())
+        .await
+        .unwrap()
+        .ok()?;
+    let addr = derive_address(index).ok()?;
     let payment = Payment {
-        id: id.clone(),
-        address: addr.clone(),
-        wif_enc,
+        id,
+        address: addr,
+        wif_enc: String::new(),
+        derivation_index: Some(index),
+        sweep_txid: None,
         amount: req.amount,
-        status: "pending".into(),
+        status: PaymentStatus::Created,
         created_at: 0,
         updated_at: 0,
         expires_at,
+        order_id: req.order_id.clone(),
+        description: req.description.clone(),
+        metadata: req.metadata.clone().unwrap_or_default(),
+        merchant_id: merchant_id.to_string(),
+        chain: Default::default(),
+        amount_delta: None,
+        late_action: None,
+        refund_address: None,
     };
+    let read_token = generate_payment_token();
+    let token_hash = hash_token(&read_token);
     let db_clone = db.clone();
     let payment_clone = payment.clone();
-    if spawn_blocking(move || db_clone.insert(&payment_clone))
+    spawn_blocking(move || db_clone.insert(&payment_clone, &token_hash))
         .await
         .unwrap()
-        .is_err()
-    {
-        return HttpResponse::InternalServerError().finish();
-    }
-    HttpResponse::Ok().json(json!({
-        "id": id,
-        "address": addr,
-        "amount": req.amount,
-        "expires_at": expires_at
-    }))
+        .ok()?;
+    Some(created_json(&payment, Some(&read_token)))
 }
 
-async fn get_payment(db: web::Data<Db>, path: web::Path<String>) -> HttpResponse {
-    let payment_id = path.into_inner();
+/// Response body of payment `id` for a replayed request, carrying the read
+/// token the original response handed out. `read_token_enc` is that token
+/// encrypted; without it (no `AES_KEY`) the replay has no read token, as the
+/// payment only stores its hash.
+async fn replay_payment(
+    db: &web::Data<Db>,
+    id: &str,
+    read_token_enc: Option<String>,
+) -> Option<serde_json::Value> {
     let db_clone = db.clone();
-    let payment_opt = spawn_blocking(move || db_clone.find(&payment_id))
+    let id = id.to_string();
+    let payment = spawn_blocking(move || db_clone.find(&id))
         .await
         .unwrap()
-        .unwrap_or(None);
+        .ok()??;
+    let read_token = read_token_enc.as_deref().and_then(decrypt_secret);
+    Some(created_json(&payment, read_token.as_deref()))
+}
+
+/// Response body of payment `id` stored by a request that died before
+/// answering, for the retry that took its claim over. That request's read
+/// token never reached anyone, so a new one replaces it.
+async fn reissue_payment(db: &web::Data<Db>, id: &str) -> Option<serde_json::Value> {
+    let read_token = generate_payment_token();
+    let token_hash = hash_token(&read_token);
+    let db_clone = db.clone();
+    let id = id.to_string();
+    let payment = spawn_blocking(move || {
+        let payment = db_clone.find(&id)?;
+        if payment.is_some() {
+            db_clone.set_read_token_hash(&id, &token_hash)?;
+        }
+        Ok::<_, rusqlite::Error>(payment)
+    })
+    .await
+    .unwrap()
+    .ok()??;
+    Some(created_json(&payment, Some(&read_token)))
+}
+
+fn created_json(payment: &Payment, read_token: Option<&str>) -> serde_json::Value {
+    json!({
+        "id": payment.id,
+        "address": payment.address,
+        "amount": format_ltc(payment.amount),
+        "amount_litoshis": payment.amount,
+        "expires_at": payment.expires_at,
+        "order_id": payment.order_id,
+        "description": payment.description,
+        "metadata": payment.metadata,
+        "merchant_id": payment.merchant_id,
+        "read_token": read_token
+    })
+}
+
+/// Merchant the caller is confined to, see [`Caller::merchant_scope`].
+fn merchant_scope(http: &HttpRequest) -> Option<String> {
+    http.extensions()
+        .get::<Caller>()
+        .and_then(|c| c.merchant_scope().map(str::to_string))
+}
+
+async fn get_payment(
+    db: web::Data<Db>,
+    policies: web::Data<Policies>,
+    http: HttpRequest,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let payment_id = path.into_inner();
+    let db_clone = db.clone();
+    let found = spawn_blocking(move || {
+        let payment = db_clone.find(&payment_id)?;
+        let txs = db_clone.payment_txs(&payment_id)?;
+        Ok::<_, rusqlite::Error>((payment, txs, db_clone.chain_tip()?))
+    })
+    .await
+    .unwrap();
+    let (payment_opt, txs, tip) = match found {
+        Ok(v) => v,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
     let Some(mut payment) = payment_opt else {
         return HttpResponse::NotFound().finish();
     };
-    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
-    if payment.status == "pending" && payment.expires_at != 0 && payment.expires_at < now {
-        let _ = db.mark_expired(&payment.id);
-        payment.status = "expired".into();
+    if merchant_scope(&http).is_some_and(|m| m != payment.merchant_id) {
+        return HttpResponse::NotFound().finish();
     }
-    let bal = match rpc_async(
-        "blockchain.scripthash.get_balance",
-        &[script_hash(&payment.address).into()],
-    )
-    .await
-    {
-        Ok(v) => v,
-        Err(_) => return HttpResponse::BadGateway().finish(),
-    };
-    let hdr = match rpc_async("blockchain.headers.subscribe", &[]).await {
-        Ok(v) => v,
-        Err(_) => return HttpResponse::BadGateway().finish(),
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    if payment.is_overdue(now, policies.settlement.underpaid_grace) {
+        let db_clone = db.clone();
+        let id = payment.id.clone();
+        let expired = spawn_blocking(move || db_clone.set_status(&id, PaymentStatus::Expired))
+            .await
+            .unwrap();
+        if let Ok(true) = expired {
+            payment.status = PaymentStatus::Expired;
+            live::publish(PaymentUpdate::status(&payment.id, payment.status));
+        }
+    }
+    let mut body = payment_json(&payment);
+    let chain = &payment.chain;
+    body["confirmations"] = json!(chain.confirmations(tip.unwrap_or(0)));
+    body["received"] = json!(format_ltc(chain.received));
+    body["received_litoshis"] = json!(chain.received);
+    body["first_seen_txid"] = json!(chain.first_seen_txid);
+    body["first_seen_at"] = json!(chain.first_seen_at);
+    body["confirmed_height"] = json!(chain.confirmed_height);
+    body["last_synced_at"] = json!(chain.last_synced_at);
+    body["transactions"] = json!(txs.iter().map(PaymentTx::to_json).collect::<Vec<_>>());
+    HttpResponse::Ok().json(body)
+}
+
+/// `POST /payments/{id}/late`: decides what happens to the funds of a
+/// `paid_late` payment: `accept` sweeps them to the payout, `refund` to
+/// `refund_address`, `review` keeps holding them.
+async fn resolve_late(
+    db: web::Data<Db>,
+    http: HttpRequest,
+    path: web::Path<String>,
+    req: web::Json<ResolveLateReq>,
+) -> HttpResponse {
+    let id = path.into_inner();
+    let ResolveLateReq {
+        action,
+        refund_address,
+    } = req.into_inner();
+    let refund_address = match (action, refund_address) {
+        (LateAction::Refund, None) => {
+            return HttpResponse::BadRequest()
+                .json(json!({ "error": "refund needs a refund_address" }));
+        }
+        (LateAction::Refund, Some(a)) if !is_segwit_v0_address(&a) => {
+            return HttpResponse::BadRequest()
+                .json(json!({ "error": "refund_address must be a segwit (ltc1…) address" }));
+        }
+        (LateAction::Refund, a) => a,
+        _ => None,
     };
-    let hist = match rpc_async(
-        "blockchain.scripthash.get_history",
-        &[script_hash(&payment.address).into()],
-    )
+    let scope = merchant_scope(&http);
+    let db_clone = db.clone();
+    let resolved = spawn_blocking(move || {
+        let Some(p) = db_clone.find(&id)? else {
+            return Ok(None);
+        };
+        if scope.is_some_and(|m| m != p.merchant_id) {
+            return Ok(None);
+        }
+        // a sweep already spending the funds settles first
+        if p.status != PaymentStatus::PaidLate
+            || db_clone.in_active_sweep(&id)?
+            || db_clone.has_unsigned_psbt(&id)?
+            || !db_clone.resolve_late(&id, action, refund_address.as_deref())?
+        {
+            return Ok(Some(Err(p.status)));
+        }
+        Ok::<_, rusqlite::Error>(db_clone.find(&id)?.map(Ok))
+    })
     .await
-    {
-        Ok(v) => v,
-        Err(_) => return HttpResponse::BadGateway().finish(),
-    };
-    let tip = hdr["height"].as_u64().unwrap_or(0);
-    let confirmations = hist
-        .as_array()
+    .unwrap();
+    match resolved {
+        Ok(Some(Ok(p))) => HttpResponse::Ok().json(payment_json(&p)),
+        Ok(Some(Err(status))) => HttpResponse::Conflict().json(json!({
+            "error": format!("payment is {status}, not a paid_late payment awaiting a decision")
+        })),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// Seconds between keepalives on an idle live stream.
+const LIVE_KEEPALIVE_SECS: u64 = 15;
+
+/// Seconds a live stream stays open at most; clients reconnect for more.
+const LIVE_MAX_LIFETIME_SECS: u64 = 3600;
+
+/// State a live stream starts from, as stored; chain fields are left out
+/// until the payment's first sync. `true` alongside it when nothing will
+/// change any more: the payment is swept, or expired and past the late
+/// payment window. `None` when the caller cannot see it.
+async fn live_snapshot(
+    db: &web::Data<Db>,
+    policies: &Policies,
+    http: &HttpRequest,
+    id: &str,
+) -> Option<(PaymentUpdate, bool)> {
+    let db_clone = db.clone();
+    let payment_id = id.to_string();
+    let (payment, tip) = spawn_blocking(move || {
+        Ok::<_, rusqlite::Error>((db_clone.find(&payment_id)?, db_clone.chain_tip()?))
+    })
+    .await
+    .unwrap()
+    .ok()?;
+    let payment = payment?;
+    if merchant_scope(http).is_some_and(|m| m != payment.merchant_id) {
+        return None;
+    }
+    let mut snapshot = PaymentUpdate::status(&payment.id, payment.status);
+    if payment.chain.last_synced_at.is_some() {
+        snapshot.received = Some(payment.chain.received);
+        snapshot.confirmations = Some(payment.chain.confirmations(tip.unwrap_or(0)));
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
         .unwrap()
-        .iter()
-        .filter(|h| h["height"].as_u64().unwrap_or(0) > 0)
-        .map(|h| tip - h["height"].as_u64().unwrap() + 1)
-        .min()
-        .unwrap_or(0);
-    let confirmed_sat = bal["confirmed"].as_i64().unwrap_or(0).max(0) as f64;
-    let unconfirmed_sat = bal["unconfirmed"].as_i64().unwrap_or(0).max(0) as f64;
-    let received = (confirmed_sat + unconfirmed_sat) / 1e8;
-    HttpResponse::Ok().json(json!({
+        .as_secs() as i64;
+    let done = snapshot.is_final()
+        || (payment.status == PaymentStatus::Expired
+            && payment.expires_at < now - policies.late.window);
+    Some((snapshot, done))
+}
+
+fn sse_frame(update: &PaymentUpdate) -> web::Bytes {
+    web::Bytes::from(format!("event: payment\ndata: {}\n\n", update.to_json()))
+}
+
+/// `GET /payments/{id}/events`: Server-Sent Events. A `payment` event with the
+/// current state, then one per change the sweeper observes, and a comment
+/// line every `LIVE_KEEPALIVE_SECS` in between. Ends once the payment is
+/// swept or can no longer change, and after `LIVE_MAX_LIFETIME_SECS`.
+async fn payment_events(
+    db: web::Data<Db>,
+    policies: web::Data<Policies>,
+    http: HttpRequest,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let id = path.into_inner();
+    let mut sub = Subscription::new(&id);
+    let Some((snapshot, done)) = live_snapshot(&db, &policies, &http, &id).await else {
+        return HttpResponse::NotFound().finish();
+    };
+    sub.seen(&snapshot);
+    let first = Some(sse_frame(&snapshot));
+    let deadline = Instant::now() + Duration::from_secs(LIVE_MAX_LIFETIME_SECS);
+    let events = stream::unfold(
+        (first, sub, done),
+        move |(first, mut sub, done)| async move {
+            if let Some(frame) = first {
+                return Some((Ok::<_, Infallible>(frame), (None, sub, done)));
+            }
+            if done || Instant::now() >= deadline {
+                return None;
+            }
+            match timeout(Duration::from_secs(LIVE_KEEPALIVE_SECS), sub.next()).await {
+                Ok(Some(u)) => {
+                    let done = u.is_final();
+                    Some((Ok(sse_frame(&u)), (None, sub, done)))
+                }
+                Ok(None) => None,
+                Err(_) => {
+                    let keepalive = web::Bytes::from_static(b": keepalive\n\n");
+                    Some((Ok(keepalive), (None, sub, false)))
+                }
+            }
+        },
+    );
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        // nginx would otherwise buffer the stream
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(events)
+}
+
+/// `GET /payments/{id}/ws`: the updates of `/events` over a WebSocket, one
+/// JSON text message each; closed when the SSE stream would end. Incoming
+/// messages other than ping and close are ignored.
+async fn payment_ws(
+    db: web::Data<Db>,
+    policies: web::Data<Policies>,
+    http: HttpRequest,
+    path: web::Path<String>,
+    body: web::Payload,
+) -> actix_web::Result<HttpResponse> {
+    let id = path.into_inner();
+    let mut sub = Subscription::new(&id);
+    let Some((snapshot, mut done)) = live_snapshot(&db, &policies, &http, &id).await else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    sub.seen(&snapshot);
+    let (response, mut session, mut messages) = actix_ws::handle(&http, body)?;
+    actix_web::rt::spawn(async move {
+        let period = Duration::from_secs(LIVE_KEEPALIVE_SECS);
+        let mut keepalive = interval_at(Instant::now() + period, period);
+        let lifetime = sleep(Duration::from_secs(LIVE_MAX_LIFETIME_SECS));
+        tokio::pin!(lifetime);
+        if session.text(snapshot.to_json().to_string()).await.is_err() {
+            return;
+        }
+        let reason = loop {
+            if done {
+                break Some(CloseCode::Normal.into());
+            }
+            select! {
+                update = sub.next() => {
+                    let Some(update) = update else { break None };
+                    done = update.is_final();
+                    if session.text(update.to_json().to_string()).await.is_err() {
+                        return;
+                    }
+                }
+                message = messages.recv() => match message {
+                    Some(Ok(Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Ok(Message::Close(reason))) => break reason,
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) | None => break None,
+                },
+                _ = keepalive.tick() => {
+                    if session.ping(b"").await.is_err() {
+                        return;
+                    }
+                }
+                _ = &mut lifetime => break Some(CloseCode::Normal.into()),
+            }
+        };
+        let _ = session.close(reason).await;
+    });
+    Ok(response)
+}
+
+fn payment_json(payment: &Payment) -> serde_json::Value {
+    json!({
         "id": payment.id,
         "address": payment.address,
-        "amount": payment.amount,
+        "amount": format_ltc(payment.amount),
+        "amount_litoshis": payment.amount,
         "status": payment.status,
+        "sweep_txid": payment.sweep_txid,
         "created_at": payment.created_at,
         "updated_at": payment.updated_at,
         "expires_at": payment.expires_at,
-        "confirmations": confirmations,
-        "received": received,
+        "order_id": payment.order_id,
+        "description": payment.description,
+        "metadata": payment.metadata,
+        "merchant_id": payment.merchant_id,
+        "amount_delta": payment.amount_delta.map(format_ltc_signed),
+        "amount_delta_litoshis": payment.amount_delta,
+        "late_action": payment.late_action,
+        "refund_address": payment.refund_address,
+    })
+}
+
+fn parse_list(req: ListReq) -> Result<(PaymentFilter, PaymentPage), String> {
+    let statuses = match &req.status {
+        Some(list) => list
+            .split(',')
+            .map(|s| s.trim().parse::<PaymentStatus>())
+            .collect::<Result<Vec<_>, _>>()?,
+        None => Vec::new(),
+    };
+    let amount = |v: &Option<String>| match v {
+        Some(v) => parse_ltc(v)
+            .map(Some)
+            .ok_or_else(|| format!("invalid amount {v:?}")),
+        None => Ok(None),
+    };
+    let metadata = match req.metadata {
+        Some(m) => {
+            let (key, value) = m
+                .split_once(':')
+                .ok_or("metadata filter must be key:value")?;
+            if key.is_empty() || key.contains(['"', '\\']) {
+                return Err(format!("invalid metadata key {key:?}"));
+            }
+            Some((key.to_string(), value.to_string()))
+        }
+        None => None,
+    };
+    let filter = PaymentFilter {
+        statuses,
+        created_after: req.created_after,
+        created_before: req.created_before,
+        updated_after: req.updated_after,
+        updated_before: req.updated_before,
+        min_amount: amount(&req.min_amount)?,
+        max_amount: amount(&req.max_amount)?,
+        address: req.address,
+        order_id: req.order_id,
+        search: req.q.filter(|q| !q.is_empty()),
+        metadata,
+        merchant_id: req.merchant_id,
+    };
+
+    let sort = match &req.sort {
+        Some(s) => s.parse::<PaymentSort>()?,
+        None => PaymentSort::default(),
+    };
+    let descending = match req.order.as_deref() {
+        None | Some("desc") => true,
+        Some("asc") => false,
+        Some(o) => return Err(format!("unknown order {o:?}")),
+    };
+    let after = match &req.cursor {
+        Some(c) => Some(decode_cursor(c, sort.as_str()).ok_or("invalid cursor for this sort")?),
+        None => None,
+    };
+    let limit = req
+        .limit
+        .unwrap_or(LIST_DEFAULT_LIMIT)
+        .clamp(1, LIST_MAX_LIMIT);
+    Ok((
+        filter,
+        PaymentPage {
+            sort,
+            descending,
+            after,
+            limit,
+        },
+    ))
+}
+
+/// Opaque cursor: hex of `kind:value:id` of the last row returned, where
+/// `kind` is the listing's sort key.
+fn encode_cursor(kind: &str, (value, id): (i64, String)) -> String {
+    hex::encode(format!("{}:{}:{}", kind, value, id))
+}
+
+fn decode_cursor(cursor: &str, kind: &str) -> Option<(i64, String)> {
+    let raw = String::from_utf8(hex::decode(cursor).ok()?).ok()?;
+    let mut parts = raw.splitn(3, ':');
+    if parts.next()? != kind {
+        return None;
+    }
+    let value = parts.next()?.parse().ok()?;
+    Some((value, parts.next()?.to_string()))
+}
+
+async fn list_payments(
+    db: web::Data<Db>,
+    http: HttpRequest,
+    req: web::Query<ListReq>,
+) -> HttpResponse {
+    let (mut filter, page) = match parse_list(req.into_inner()) {
+        Ok(v) => v,
+        Err(e) => return HttpResponse::BadRequest().json(json!({ "error": e })),
+    };
+    if let Some(m) = merchant_scope(&http) {
+        filter.merchant_id = Some(m);
+    }
+    let db_clone = db.clone();
+    let page_clone = page.clone();
+    let payments = match spawn_blocking(move || db_clone.list_payments(&filter, &page_clone))
+        .await
+        .unwrap()
+    {
+        Ok(p) => p,
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+    let next_cursor = match payments.last() {
+        Some(last) if payments.len() == page.limit => {
+            Some(encode_cursor(page.sort.as_str(), page.sort.key(last)))
+        }
+        _ => None,
+    };
+    HttpResponse::Ok().json(json!({
+        "data": payments.iter().map(payment_json).collect::<Vec<_>>(),
+        "next_cursor": next_cursor,
+    }))
+}
+
+async fn list_psbts(db: web::Data<Db>) -> HttpResponse {
+    let db_clone = db.clone();
+    match spawn_blocking(move || db_clone.unsigned_psbts())
+        .await
+        .unwrap()
+    {
+        Ok(psbts) => HttpResponse::Ok().json(psbts),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+async fn get_psbt(db: web::Data<Db>, path: web::Path<String>) -> HttpResponse {
+    let psbt_id = path.into_inner();
+    let db_clone = db.clone();
+    match spawn_blocking(move || db_clone.find_psbt(&psbt_id))
+        .await
+        .unwrap()
+    {
+        Ok(Some(psbt)) => HttpResponse::Ok().json(psbt),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+async fn submit_psbt(
+    db: web::Data<Db>,
+    path: web::Path<String>,
+    req: web::Json<SubmitPsbtReq>,
+) -> HttpResponse {
+    match psbt::submit(&db, &path.into_inner(), &req.psbt).await {
+        Ok(txid) => HttpResponse::Ok().json(json!({ "txid": txid })),
+        Err(e) => HttpResponse::BadRequest().json(json!({ "error": format!("{e:#}") })),
+    }
+}
+
+async fn cancel_psbt(db: web::Data<Db>, path: web::Path<String>) -> HttpResponse {
+    let psbt_id = path.into_inner();
+    let db_clone = db.clone();
+    match spawn_blocking(move || psbt::cancel(&db_clone, &psbt_id))
+        .await
+        .unwrap()
+    {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => HttpResponse::BadRequest().json(json!({ "error": format!("{e:#}") })),
+    }
+}
+
+/// Cursor kind of the delivery log, which is always newest first.
+const DELIVERY_CURSOR: &str = "deliveries";
+
+fn delivery_json(ev: &OutboxEvent) -> serde_json::Value {
+    json!({
+        "id": ev.id,
+        "event": ev.event,
+        "payment_id": ev.payment_id,
+        "merchant_id": ev.merchant_id,
+        "status": ev.status,
+        "attempts": ev.attempts,
+        "next_attempt_at": ev.next_attempt_at,
+        "last_error": ev.last_error,
+        "created_at": ev.created_at,
+        "updated_at": ev.updated_at,
+        "delivered_at": ev.delivered_at,
+        "payload": serde_json::from_str::<serde_json::Value>(&ev.payload).unwrap_or_default(),
+    })
+}
+
+/// `GET /webhooks/deliveries`: the outbox, newest first, keyset-paginated
+/// like `GET /payments`.
+async fn list_deliveries(
+    db: web::Data<Db>,
+    http: HttpRequest,
+    req: web::Query<DeliveryListReq>,
+) -> HttpResponse {
+    let req = req.into_inner();
+    let statuses: Vec<String> = match &req.status {
+        Some(list) => list.split(',').map(|s| s.trim().to_string()).collect(),
+        None => Vec::new(),
+    };
+    if let Some(s) = statuses
+        .iter()
+        .find(|s| !DELIVERY_STATUSES.contains(&s.as_str()))
+    {
+        return HttpResponse::BadRequest()
+            .json(json!({ "error": format!("unknown status {s:?}") }));
+    }
+    let after = match &req.cursor {
+        Some(c) => match decode_cursor(c, DELIVERY_CURSOR) {
+            Some(a) => Some(a),
+            None => return HttpResponse::BadRequest().json(json!({ "error": "invalid cursor" })),
+        },
+        None => None,
+    };
+    let filter = WebhookEventFilter {
+        merchant_id: merchant_scope(&http).or(req.merchant_id),
+        payment_id: req.payment_id,
+        statuses,
+        created_after: req.created_after,
+        created_before: req.created_before,
+    };
+    let limit = req
+        .limit
+        .unwrap_or(LIST_DEFAULT_LIMIT)
+        .clamp(1, LIST_MAX_LIMIT);
+    let db_clone = db.clone();
+    let events =
+        match spawn_blocking(move || db_clone.list_webhook_events(&filter, after.as_ref(), limit))
+            .await
+            .unwrap()
+        {
+            Ok(e) => e,
+            Err(_) => return HttpResponse::InternalServerError().finish(),
+        };
+    let next_cursor = match events.last() {
+        Some(last) if events.len() == limit => Some(encode_cursor(
+            DELIVERY_CURSOR,
+            (last.created_at, last.id.clone()),
+        )),
+        _ => None,
+    };
+    HttpResponse::Ok().json(json!({
+        "data": events.iter().map(delivery_json).collect::<Vec<_>>(),
+        "next_cursor": next_cursor,
     }))
 }
+
+/// `GET /webhooks/deliveries/{id}`: one event with every attempt.
+async fn get_delivery(
+    db: web::Data<Db>,
+    http: HttpRequest,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let id = path.into_inner();
+    let scope = merchant_scope(&http);
+    let db_clone = db.clone();
+    let found = spawn_blocking(move || {
+        let ev = db_clone.find_webhook_event(&id)?;
+        let Some(ev) = ev.filter(|ev| scope.is_none_or(|m| m == ev.merchant_id)) else {
+            return Ok(None);
+        };
+        let attempts = db_clone.webhook_attempts(&id)?;
+        Ok::<_, rusqlite::Error>(Some((ev, attempts)))
+    })
+    .await
+    .unwrap();
+    match found {
+        Ok(Some((ev, attempts))) => {
+            let mut body = delivery_json(&ev);
+            body["attempt_log"] = json!(attempts);
+            HttpResponse::Ok().json(body)
+        }
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// `POST /webhooks/deliveries/{id}/replay`: sends the event again right away,
+/// delivered or not.
+async fn replay_delivery(
+    db: web::Data<Db>,
+    http: HttpRequest,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let id = path.into_inner();
+    let scope = merchant_scope(&http);
+    let db_clone = db.clone();
+    let replayed = spawn_blocking(move || {
+        let ev = db_clone.find_webhook_event(&id)?;
+        if ev.is_none_or(|ev| scope.is_some_and(|m| m != ev.merchant_id)) {
+            return Ok(false);
+        }
+        db_clone.replay_webhook_event(&id)
+    })
+    .await
+    .unwrap();
+    match replayed {
+        Ok(true) => HttpResponse::Accepted().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+/// `POST /webhooks/deliveries/replay`: after a receiver outage, sends again
+/// every failed event created in `[since, until)` (`until` defaults to now).
+async fn replay_deliveries(
+    db: web::Data<Db>,
+    http: HttpRequest,
+    req: web::Json<ReplayReq>,
+) -> HttpResponse {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let (since, until) = (req.since, req.until.unwrap_or(now + 1));
+    if since >= until {
+        return HttpResponse::BadRequest().json(json!({ "error": "since must be before until" }));
+    }
+    let scope = merchant_scope(&http);
+    let db_clone = db.clone();
+    match spawn_blocking(move || {
+        db_clone.replay_failed_webhook_events(since, until, scope.as_deref())
+    })
+    .await
+    .unwrap()
+    {
+        Ok(n) => HttpResponse::Accepted().json(json!({ "replayed": n })),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -1,31 +1,190 @@
 use crate::{
-    db::{Db, Payment},
-    electrum::{fee_sat_async, rpc_async},
-    utils::{decrypt_wif, script_hash},
-    webhook::send_completion_webhook,
+    db::{
+        Db, LateAction, Payment, PaymentFilter, PaymentPage, PaymentSort, PaymentStatus, PaymentTx,
+        Sweep, SweepInput,
+    },
+    electrum::{fee_rate_async, rpc_async},
+    live::{self, PaymentUpdate},
+    psbt,
+    utils::{decrypt_wif, parse_ltc, script_hash},
+    wallet::{derive_secret, is_watch_only, payout_address},
+    watcher::{self, WatchEvent},
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use bech32::{decode, FromBase32};
-use bitcoin::Witness;
 use bitcoin::{
     blockdata::{script::Script, transaction::OutPoint},
     util::{psbt::serialize::Serialize, sighash::SighashCache},
     EcdsaSighashType, PubkeyHash, Transaction, TxIn, TxOut, Txid,
 };
+use bitcoin::{consensus::deserialize, Witness};
 use bitcoin::{hashes::Hash, util::address::WitnessVersion};
 use ripemd::Ripemd160;
 use secp256k1::{PublicKey, Secp256k1, SecretKey};
 use sha2::{Digest, Sha256};
 use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashSet},
     env,
     str::FromStr,
+    sync::Arc,
     time::{SystemTime, UNIX_EPOCH},
 };
 use tokio::{
-    spawn,
+    select, spawn,
+    sync::mpsc::unbounded_channel,
     time::{interval, Duration},
 };
-use tracing::error;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+/// Witness bytes of a P2WPKH spend (signature + pubkey) in vbytes, which an
+/// unsigned transaction's vsize does not include yet.
+const WITNESS_VBYTES_PER_INPUT: u64 = 27;
+
+/// nSequence signalling opt-in replace-by-fee (BIP125) so stuck sweeps can be bumped.
+const RBF_SEQUENCE: u32 = 0xffff_fffd;
+
+/// A payment whose confirmed funds are ready to be swept.
+pub struct Ready {
+    pub payment: Payment,
+    pub utxos: Vec<(OutPoint, u64)>,
+}
+
+/// Limits on how much one sweep transaction may spend.
+struct BatchLimits {
+    max_inputs: usize,
+    /// litoshis; 0 means unlimited
+    max_value: u64,
+}
+
+impl BatchLimits {
+    fn from_env() -> Self {
+        let max_inputs = env::var("SWEEP_BATCH_MAX_INPUTS")
+            .unwrap_or_else(|_| "100".into())
+            .parse::<usize>()
+            .unwrap_or(100)
+            .max(1);
+        let max_value = env::var("SWEEP_BATCH_MAX_VALUE")
+            .ok()
+            .and_then(|v| parse_ltc(&v))
+            .unwrap_or(0);
+        Self {
+            max_inputs,
+            max_value,
+        }
+    }
+}
+
+/// What happens to funds confirmed on an expired payment.
+pub struct LatePolicy {
+    pub action: LateAction,
+    /// seconds after expiry the address is still watched for late funds;
+    /// older ones are only found by the cold scan
+    pub window: i64,
+}
+
+impl LatePolicy {
+    pub fn from_env() -> Self {
+        Self {
+            action: env::var("LATE_PAYMENT_POLICY")
+                .unwrap_or_else(|_| "accept".into())
+                .parse::<LateAction>()
+                .unwrap_or(LateAction::Accept),
+            window: env::var("LATE_PAYMENT_WINDOW")
+                .unwrap_or_else(|_| "604800".into())
+                .parse::<u64>()
+                .unwrap_or(604800) as i64,
+        }
+    }
+}
+
+/// How far a confirmed amount may be off the requested one, and what
+/// happens then.
+pub struct SettlementPolicy {
+    /// litoshis
+    under_tolerance: u64,
+    /// percent of the requested amount
+    under_tolerance_pct: f64,
+    /// litoshis
+    over_tolerance: u64,
+    /// percent of the requested amount
+    over_tolerance_pct: f64,
+    /// settle a payment within tolerance as `paid` rather than
+    /// `underpaid`/`overpaid`
+    accept_within_tolerance: bool,
+    /// seconds an underpaid payment stays open past its expiry for a top-up
+    pub underpaid_grace: i64,
+}
+
+impl SettlementPolicy {
+    pub fn from_env() -> Self {
+        let ltc = |name: &str| env::var(name).ok().and_then(|v| parse_ltc(&v)).unwrap_or(0);
+        let percent = |name: &str| {
+            env::var(name)
+                .unwrap_or_else(|_| "0".into())
+                .parse::<f64>()
+                .unwrap_or(0.0)
+                .clamp(0.0, 100.0)
+        };
+        Self {
+            under_tolerance: ltc("UNDERPAYMENT_TOLERANCE"),
+            under_tolerance_pct: percent("UNDERPAYMENT_TOLERANCE_PERCENT"),
+            over_tolerance: ltc("OVERPAYMENT_TOLERANCE"),
+            over_tolerance_pct: percent("OVERPAYMENT_TOLERANCE_PERCENT"),
+            accept_within_tolerance: env::var("ACCEPT_WITHIN_TOLERANCE")
+                .unwrap_or_else(|_| "true".into())
+                .parse::<bool>()
+                .unwrap_or(true),
+            underpaid_grace: env::var("UNDERPAYMENT_GRACE")
+                .unwrap_or_else(|_| "0".into())
+                .parse::<u64>()
+                .unwrap_or(0) as i64,
+        }
+    }
+
+    /// Settled status of a payment of `amount` with `confirmed` litoshis.
+    /// The larger of the absolute and the percentage tolerance applies.
+    pub fn outcome(&self, amount: u64, confirmed: u64) -> PaymentStatus {
+        let (delta, abs, pct, beyond) = match confirmed.cmp(&amount) {
+            Ordering::Equal => return PaymentStatus::Paid,
+            Ordering::Less => (
+                amount - confirmed,
+                self.under_tolerance,
+                self.under_tolerance_pct,
+                PaymentStatus::Underpaid,
+            ),
+            Ordering::Greater => (
+                confirmed - amount,
+                self.over_tolerance,
+                self.over_tolerance_pct,
+                PaymentStatus::Overpaid,
+            ),
+        };
+        let tolerance = abs.max((amount as f64 * pct / 100.0) as u64);
+        if self.accept_within_tolerance && delta <= tolerance {
+            PaymentStatus::Paid
+        } else {
+            beyond
+        }
+    }
+}
+
+/// The settlement and late-payment policies, read once at startup and
+/// shared by the sweeper and the API.
+pub struct Policies {
+    pub settlement: SettlementPolicy,
+    pub late: LatePolicy,
+}
+
+impl Policies {
+    pub fn from_env() -> Self {
+        Self {
+            settlement: SettlementPolicy::from_env(),
+            late: LatePolicy::from_env(),
+        }
+    }
+}
 
 fn addr_to_script(addr: &str) -> Script {
     let (_, data, _) = decode(addr).unwrap();
@@ -36,7 +195,7 @@ fn addr_to_script(addr: &str) -> Script {
 
 fn hash160(data: &[u8]) -> [u8; 20] {
     let mut out = [0u8; 20];
-    out.copy_from_slice(&Ripemd160::digest(&Sha256::digest(data)));
+    out.copy_from_slice(&Ripemd160::digest(Sha256::digest(data)));
     out
 }
 
@@ -46,69 +205,259 @@ fn p2pkh_script_code(pk: &PublicKey) -> Script {
     Script::new_p2pkh(&pubkey_hash)
 }
 
-pub async fn start(db: Db) {
+pub async fn start(db: Db, policies: Arc<Policies>) {
+    let (tx, mut rx) = unbounded_channel();
+    watcher::start(db.clone(), tx, policies.late.window);
+
     spawn(async move {
         let mut iv = interval(Duration::from_secs(10));
         let mut cycle: u64 = 0;
         loop {
-            iv.tick().await;
-            cycle += 1;
-            let payments = db.all().unwrap_or_default();
-            for p in &payments {
-                let cold = p.status != "pending";
-                if cold && cycle % 360 != 0 {
-                    continue;
+            // everything that becomes sweep-ready in one pass goes into one batch
+            let mut ready = Vec::new();
+            select! {
+                ev = rx.recv() => {
+                    let Some(ev) = ev else { break };
+                    // coalesce a burst (e.g. a block touching many payments) into one pass
+                    let mut batch = HashSet::from([ev]);
+                    while let Ok(ev) = rx.try_recv() {
+                        batch.insert(ev);
+                    }
+                    if batch.contains(&WatchEvent::Resync) {
+                        let now = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .unwrap()
+                            .as_secs() as i64;
+                        let late_since = now - policies.late.window;
+                        for p in db.awaiting_funds(late_since).unwrap_or_default() {
+                            if let Ok(Some(r)) = process(&db, &p, &policies).await {
+                                ready.push(r);
+                            }
+                        }
+                    } else {
+                        for ev in batch {
+                            let WatchEvent::Payment(id) = ev else { continue };
+                            if let Ok(Some(p)) = db.find(&id) {
+                                if let Ok(Some(r)) = process(&db, &p, &policies).await {
+                                    ready.push(r);
+                                }
+                            }
+                        }
+                    }
+                }
+                _ = iv.tick() => {
+                    cycle += 1;
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+                    match db.expire_overdue(now, policies.settlement.underpaid_grace) {
+                        Ok(ids) if !ids.is_empty() => {
+                            info!(count = ids.len(), "expired overdue payments");
+                            for id in &ids {
+                                live::publish(PaymentUpdate::status(id, PaymentStatus::Expired));
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => error!(error = %e, "failed to expire overdue payments"),
+                    }
+                    if cycle.is_multiple_of(6) {
+                        if let Err(e) = track_sweeps(&db, &policies).await {
+                            error!(error = %e, "sweep tracking failed");
+                        }
+                        if let Err(e) = psbt::discard_stale(&db).await {
+                            error!(error = %e, "stale psbt check failed");
+                        }
+                    }
+                    // safety net for missed notifications and cold entries
+                    if cycle.is_multiple_of(360) {
+                        ready.extend(cold_scan(&db, &policies).await);
+                    }
+                }
+            }
+            if !ready.is_empty() {
+                if let Err(e) = sweep_ready(&db, ready).await {
+                    error!(error = %e, "sweep failed");
                 }
-                let _ = process(&db, p).await;
             }
         }
     });
 }
 
-async fn process(db: &Db, p: &Payment) -> Result<()> {
-    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
-    if p.status == "pending" && p.expires_at != 0 && p.expires_at < now {
-        db.mark_expired(&p.id)?;
+/// Re-checks every payment not yet swept, a page at a time, and returns the
+/// ones ready to be swept.
+async fn cold_scan(db: &Db, policies: &Policies) -> Vec<Ready> {
+    let filter = PaymentFilter {
+        statuses: PaymentStatus::ALL
+            .into_iter()
+            .filter(|s| *s != PaymentStatus::Swept)
+            .collect(),
+        ..Default::default()
+    };
+    let mut page = PaymentPage {
+        sort: PaymentSort::CreatedAt,
+        descending: false,
+        after: None,
+        limit: 500,
+    };
+    let mut ready = Vec::new();
+    loop {
+        let payments = match db.list_payments(&filter, &page) {
+            Ok(p) => p,
+            Err(e) => {
+                error!(error = %e, "cold scan query failed");
+                break;
+            }
+        };
+        for p in &payments {
+            if let Ok(Some(r)) = process(db, p, policies).await {
+                ready.push(r);
+            }
+        }
+        match payments.last() {
+            Some(last) if payments.len() == page.limit => {
+                page.after = Some(page.sort.key(last));
+            }
+            _ => break,
+        }
     }
+    ready
+}
 
-    let hist = rpc_async(
-        "blockchain.scripthash.get_history",
-        &[script_hash(&p.address).into()],
-    )
-    .await?;
-    let hdr = rpc_async("blockchain.headers.subscribe", &[]).await?;
-    let tip = hdr["height"].as_u64().unwrap_or(0);
-    let confirmations = hist
-        .as_array()
-        .unwrap()
-        .iter()
-        .filter(|h| h["height"].as_u64().unwrap_or(0) > 0)
-        .map(|h| tip - h["height"].as_u64().unwrap() + 1)
-        .min()
-        .unwrap_or(0);
+/// Whether funds confirmed at an expired payment's address after its
+/// deadline, by the time of the block that mined them; outputs recorded
+/// before block times were fall back to when a sync first saw them mined.
+/// An underpayment confirmed before it is not a late payment.
+fn paid_late(p: &Payment, txs: &[PaymentTx]) -> bool {
+    txs.iter().any(|t| {
+        !t.double_spent
+            && t.block_time
+                .or(t.confirmed_at)
+                .is_some_and(|at| at > p.expires_at)
+    })
+}
 
-    let needed = env::var("CONFIRMATIONS")
-        .unwrap_or_else(|_| "2".into())
-        .parse::<u64>()
-        .unwrap_or(2);
-    if confirmations < needed {
-        return Ok(());
+/// Where an expired payment whose funds reached `CONFIRMATIONS` goes, with
+/// `settled` the status they would give an open one. Funds that all
+/// confirmed by the deadline and cover the amount settle it as usual, e.g.
+/// when it expired before they were synced. Otherwise it becomes `paid_late`:
+/// late funds handled as `late` says, an on-time underpayment held for review.
+fn expired_outcome(
+    p: &Payment,
+    txs: &[PaymentTx],
+    settled: PaymentStatus,
+    late: LateAction,
+) -> (PaymentStatus, Option<LateAction>) {
+    if paid_late(p, txs) {
+        return (PaymentStatus::PaidLate, Some(late));
+    }
+    match settled {
+        PaymentStatus::Paid | PaymentStatus::Overpaid => (settled, None),
+        _ => (PaymentStatus::PaidLate, Some(LateAction::Review)),
+    }
+}
+
+/// Checks one payment against the chain, advances its status and returns
+/// its UTXOs if they are ready to be swept. Settled payments (`paid`,
+/// `overpaid`) are swept right away. `underpaid` ones wait for a top-up
+/// until their grace window ends. An `expired` payment with confirmed funds
+/// settles or becomes `paid_late`, see [`expired_outcome`].
+async fn process(db: &Db, p: &Payment, policies: &Policies) -> Result<Option<Ready>> {
+    if p.status == PaymentStatus::Swept {
+        return Ok(None);
+    }
+    // funds are already on their way; the tracker owns them until the sweep settles
+    if db.in_active_sweep(&p.id)? {
+        return Ok(None);
+    }
+    let settlement = &policies.settlement;
+    let mut status = p.status;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    if p.is_overdue(now, settlement.underpaid_grace)
+        && db.set_status(&p.id, PaymentStatus::Expired)?
+    {
+        status = PaymentStatus::Expired;
     }
 
-    let bal = rpc_async(
-        "blockchain.scripthash.get_balance",
-        &[script_hash(&p.address).into()],
-    )
-    .await?;
-    let confirmed_balance = bal["confirmed"].as_u64().unwrap_or(0);
+    let (chain, tip) = watcher::sync(db, p).await?;
+    if chain.first_seen_txid.is_none() {
+        live::publish(PaymentUpdate {
+            payment_id: p.id.clone(),
+            status,
+            received: Some(0),
+            confirmations: Some(0),
+        });
+        return Ok(None);
+    }
+    let confirmations = chain.confirmations(tip);
+    let confirmed_balance = chain.confirmed;
 
-    let sweep_threshold = if p.status == "pending" {
-        (p.amount * 1e8) as u64
+    let needed = match db
+        .find_merchant(&p.merchant_id)?
+        .and_then(|m| m.confirmations)
+    {
+        Some(n) => n,
+        None => env::var("CONFIRMATIONS")
+            .unwrap_or_else(|_| "2".into())
+            .parse::<u64>()
+            .unwrap_or(2),
+    };
+    // what to do with the funds, if the payment becomes `paid_late`
+    let mut late_for = None;
+    let observed = if chain.received == 0 {
+        // everything seen was replaced or reorged out
+        PaymentStatus::Created
+    } else if confirmations < needed {
+        if confirmed_balance == 0 {
+            PaymentStatus::SeenInMempool
+        } else {
+            PaymentStatus::Confirming
+        }
+    } else if status == PaymentStatus::Expired {
+        let settled = settlement.outcome(p.amount, confirmed_balance);
+        let txs = db.payment_txs(&p.id)?;
+        let (observed, action) = expired_outcome(p, &txs, settled, policies.late.action);
+        late_for = action;
+        observed
     } else {
-        1
+        settlement.outcome(p.amount, confirmed_balance)
     };
-    if confirmed_balance < sweep_threshold {
-        return Ok(());
+    let mut late_action = p.late_action;
+    if status.can_become(observed) {
+        let delta = matches!(
+            observed,
+            PaymentStatus::Underpaid
+                | PaymentStatus::Paid
+                | PaymentStatus::Overpaid
+                | PaymentStatus::PaidLate
+        )
+        .then(|| confirmed_balance as i64 - p.amount as i64);
+        if db.settle(&p.id, observed, delta, late_for)? {
+            info!(payment_id = %p.id, from = %status, to = %observed, "payment status changed");
+            status = observed;
+            late_action = late_for.or(late_action);
+        }
+    }
+    live::publish(PaymentUpdate {
+        payment_id: p.id.clone(),
+        status,
+        received: Some(chain.received),
+        confirmations: Some(confirmations),
+    });
+
+    let sweepable = match status {
+        PaymentStatus::Paid | PaymentStatus::Overpaid => true,
+        // late funds wait for a decision, or for somewhere to refund them to
+        PaymentStatus::PaidLate => match late_action {
+            Some(LateAction::Accept) => true,
+            Some(LateAction::Refund) => p.refund_address.is_some(),
+            _ => false,
+        },
+        _ => false,
+    };
+    if !sweepable || confirmations < needed || confirmed_balance == 0 {
+        return Ok(None);
+    }
+
+    if db.has_unsigned_psbt(&p.id)? {
+        return Ok(None);
     }
 
     let utxos = rpc_async(
@@ -116,8 +465,127 @@ async fn process(db: &Db, p: &Payment) -> Result<()> {
         &[script_hash(&p.address).into()],
     )
     .await?;
-    let main_address = env::var("MAIN_ADDRESS").unwrap();
-    let main_script = addr_to_script(&main_address);
+    let mut outs = Vec::new();
+    for u in utxos.as_array().unwrap() {
+        let txid = Txid::from_str(u["tx_hash"].as_str().unwrap())?;
+        let vout = u["tx_pos"].as_u64().unwrap() as u32;
+        outs.push((OutPoint::new(txid, vout), u["value"].as_u64().unwrap()));
+    }
+    if outs.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Ready {
+        payment: p.clone(),
+        utxos: outs,
+    }))
+}
+
+/// Splits ready payments into batches within `SWEEP_BATCH_MAX_INPUTS` /
+/// `SWEEP_BATCH_MAX_VALUE`. A payment's UTXOs are never split across batches.
+fn batches(ready: Vec<Ready>, limits: &BatchLimits) -> Vec<Vec<Ready>> {
+    let mut out: Vec<Vec<Ready>> = Vec::new();
+    let mut cur: Vec<Ready> = Vec::new();
+    let (mut inputs, mut value) = (0usize, 0u64);
+    for r in ready {
+        let r_inputs = r.utxos.len();
+        let r_value: u64 = r.utxos.iter().map(|(_, v)| v).sum();
+        let over_inputs = inputs + r_inputs > limits.max_inputs;
+        let over_value = limits.max_value > 0 && value + r_value > limits.max_value;
+        if !cur.is_empty() && (over_inputs || over_value) {
+            out.push(std::mem::take(&mut cur));
+            inputs = 0;
+            value = 0;
+        }
+        inputs += r_inputs;
+        value += r_value;
+        cur.push(r);
+    }
+    if !cur.is_empty() {
+        out.push(cur);
+    }
+    out
+}
+
+/// Where merchant `merchant_id`'s funds go: its payout address, the next
+/// unused address of its payout xpub (with its index, to be marked used once
+/// a sweep pays it), or `MAIN_ADDRESS` for the `default` merchant without
+/// either.
+fn payout_destination(db: &Db, merchant_id: &str) -> Result<(String, Option<u32>)> {
+    let m = db
+        .find_merchant(merchant_id)?
+        .ok_or_else(|| anyhow!("merchant {merchant_id} not found"))?;
+    if let Some(address) = m.payout_address {
+        return Ok((address, None));
+    }
+    if let Some(xpub) = m.payout_xpub {
+        let address = payout_address(&xpub, m.payout_index)?;
+        return Ok((address, Some(m.payout_index)));
+    }
+    if m.is_default() {
+        return Ok((env::var("MAIN_ADDRESS")?, None));
+    }
+    Err(anyhow!("merchant {merchant_id} has no payout destination"))
+}
+
+/// Sweeps every ready payment to its merchant's payout destination, as few
+/// multi-input transactions as the batch limits allow; a batch never mixes
+/// merchants. In watch-only mode HD payments are exported as one PSBT per
+/// batch instead.
+pub async fn sweep_ready(db: &Db, ready: Vec<Ready>) -> Result<()> {
+    let limits = BatchLimits::from_env();
+    // refunds leave in their own transactions, to the payer's address
+    let mut by_destination: BTreeMap<(String, Option<String>), Vec<Ready>> = BTreeMap::new();
+    for r in ready {
+        let refund_to = r.payment.refund_to().map(str::to_string);
+        by_destination
+            .entry((r.payment.merchant_id.clone(), refund_to))
+            .or_default()
+            .push(r);
+    }
+
+    for ((merchant_id, refund_to), ready) in by_destination {
+        let (external, signable): (Vec<Ready>, Vec<Ready>) = ready
+            .into_iter()
+            .partition(|r| r.payment.derivation_index.is_some() && is_watch_only());
+        let all = batches(signable, &limits)
+            .into_iter()
+            .map(|b| (b, false))
+            .chain(batches(external, &limits).into_iter().map(|b| (b, true)));
+        for (batch, export) in all {
+            let ids: Vec<String> = batch.iter().map(|r| r.payment.id.clone()).collect();
+            let (destination, payout_index) = match refund_to
+                .clone()
+                .map_or_else(|| payout_destination(db, &merchant_id), |a| Ok((a, None)))
+            {
+                Ok(d) => d,
+                Err(e) => {
+                    error!(%merchant_id, payment_ids = ?ids, error = %e, "no payout destination");
+                    continue;
+                }
+            };
+            match sweep_batch(db, batch, &destination, export).await {
+                Ok(true) => {
+                    if let Some(index) = payout_index {
+                        if let Err(e) = db.use_payout_index(&merchant_id, index) {
+                            error!(%merchant_id, index, error = %e, "payout index not advanced");
+                        }
+                    }
+                }
+                Ok(false) => {}
+                Err(e) if export => {
+                    error!(payment_ids = ?ids, error = %e, "psbt export failed")
+                }
+                Err(e) => error!(payment_ids = ?ids, error = %e, "batch sweep failed"),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sweeps or exports one batch to `destination`; `false` if it was not worth
+/// its fee and nothing was recorded.
+async fn sweep_batch(db: &Db, batch: Vec<Ready>, destination: &str, export: bool) -> Result<bool> {
+    let main_script = addr_to_script(destination);
 
     let mut total = 0u64;
     let mut tx = Transaction {
@@ -129,58 +597,589 @@ async fn process(db: &Db, p: &Payment) -> Result<()> {
             script_pubkey: main_script,
         }],
     };
-    let mut prev_values = Vec::new();
-    for u in utxos.as_array().unwrap() {
-        let val = u["value"].as_u64().unwrap();
-        total += val;
-        prev_values.push(val);
-        let txid = Txid::from_str(u["tx_hash"].as_str().unwrap())?;
-        let vout = u["tx_pos"].as_u64().unwrap() as u32;
-        tx.input.push(TxIn {
-            previous_output: OutPoint::new(txid, vout),
-            script_sig: Script::new(),
-            sequence: 0xffffffff,
-            witness: Witness::default(),
-        });
+    // (index into batch, value) for each input, in input order
+    let mut owners = Vec::new();
+    for (i, r) in batch.iter().enumerate() {
+        for (outpoint, val) in &r.utxos {
+            total += val;
+            owners.push((i, *val));
+            tx.input.push(TxIn {
+                previous_output: *outpoint,
+                script_sig: Script::new(),
+                sequence: RBF_SEQUENCE,
+                witness: Witness::default(),
+            });
+        }
     }
 
-    let fee = fee_sat_async(tx.vsize() as u64 + 68).await;
+    let fee_rate = fee_rate_async().await;
+    let fee = estimated_vsize(&tx) * fee_rate;
     if total <= fee {
-        return Ok(());
+        return Ok(false);
     }
     tx.output[0].value = total - fee;
 
-    let sk_hex = decrypt_wif(&p.wif_enc);
-    let sk = SecretKey::from_str(&sk_hex)?;
-    let secp = Secp256k1::new();
-    let pk = PublicKey::from_secret_key(&secp, &sk);
+    if export {
+        let inputs: Vec<(&Payment, u64)> = owners
+            .iter()
+            .map(|(i, v)| (&batch[*i].payment, *v))
+            .collect();
+        psbt::export(db, tx, &inputs, None)?;
+        return Ok(true);
+    }
+
+    let keys = batch
+        .iter()
+        .map(|r| signing_key(&r.payment))
+        .collect::<Result<Vec<_>>>()?;
+    let spends: Vec<(SecretKey, u64)> = owners.iter().map(|(o, v)| (keys[*o], *v)).collect();
+    sign_inputs(&mut tx, &spends)?;
+
+    let inputs = owners
+        .iter()
+        .zip(&tx.input)
+        .map(|((owner, value), txin)| {
+            let p = &batch[*owner].payment;
+            SweepInput {
+                txid: txin.previous_output.txid.to_string(),
+                vout: txin.previous_output.vout,
+                value: *value,
+                payment_id: p.id.clone(),
+                address: p.address.clone(),
+            }
+        })
+        .collect();
+    broadcast_sweep(db, &tx, fee, fee_rate, inputs).await?;
+    Ok(true)
+}
 
-    for (i, prev_value) in prev_values.iter().enumerate() {
+/// vsize of `tx` once every input carries a P2WPKH witness.
+fn estimated_vsize(tx: &Transaction) -> u64 {
+    tx.vsize() as u64 + WITNESS_VBYTES_PER_INPUT * tx.input.len() as u64
+}
+
+fn signing_key(p: &Payment) -> Result<SecretKey> {
+    Ok(match p.derivation_index {
+        Some(index) => derive_secret(index)?,
+        None => SecretKey::from_str(&decrypt_wif(&p.wif_enc))?,
+    })
+}
+
+/// Signs every P2WPKH input of `tx`; `spends[i]` is the key and value of input `i`.
+fn sign_inputs(tx: &mut Transaction, spends: &[(SecretKey, u64)]) -> Result<()> {
+    let secp = Secp256k1::new();
+    for (i, (sk, prev_value)) in spends.iter().enumerate() {
+        let pk = PublicKey::from_secret_key(&secp, sk);
         let script_code = p2pkh_script_code(&pk);
         let sighash = {
-            let mut cache = SighashCache::new(&mut tx);
+            let mut cache = SighashCache::new(&mut *tx);
             cache.segwit_signature_hash(i, &script_code, *prev_value, EcdsaSighashType::All)?
         };
         let msg = secp256k1::Message::from_slice(&sighash[..])?;
-        let mut sig: Vec<u8> = secp.sign_ecdsa(&msg, &sk).serialize_der().to_vec();
+        let mut sig: Vec<u8> = secp.sign_ecdsa(&msg, sk).serialize_der().to_vec();
         sig.push(EcdsaSighashType::All.to_u32() as u8);
+        tx.input[i].witness = Witness::default();
         tx.input[i].witness.push(sig);
-        tx.input[i].witness.push(pk.serialize().to_vec());
+        tx.input[i].witness.push(pk.serialize());
     }
+    Ok(())
+}
 
+/// Broadcasts a signed sweep and records it; its payments keep their status
+/// with `sweep_txid` set until [`track_sweeps`] sees it confirm.
+pub async fn broadcast_sweep(
+    db: &Db,
+    tx: &Transaction,
+    fee: u64,
+    fee_rate: u64,
+    inputs: Vec<SweepInput>,
+) -> Result<String> {
+    let raw_hex = hex::encode(tx.serialize());
+    // read up front: once the transaction is out it has to be recorded
+    let tip = broadcast_tip(db).await?;
     rpc_async(
         "blockchain.transaction.broadcast",
-        &[hex::encode(tx.serialize()).into()],
+        &[raw_hex.clone().into()],
     )
     .await?;
-    
-    db.mark_completed(&p.id)?;
-    
-    if let Ok(Some(updated_payment)) = db.find(&p.id) {
-        if let Err(e) = send_completion_webhook(&updated_payment).await {
-            error!(payment_id = %p.id, error = %e, "Failed to send webhook");
+    let txid = tx.txid().to_string();
+
+    let mut payment_ids: Vec<String> = Vec::new();
+    for i in &inputs {
+        if !payment_ids.contains(&i.payment_id) {
+            payment_ids.push(i.payment_id.clone());
         }
     }
-    
+    info!(%txid, payments = payment_ids.len(), inputs = inputs.len(), fee, fee_rate, "broadcast sweep");
+
+    db.record_sweep(&Sweep {
+        id: Uuid::new_v4().to_string(),
+        txid: txid.clone(),
+        raw_hex,
+        fee,
+        fee_rate,
+        inputs,
+        payment_ids,
+        status: "broadcast".into(),
+        broadcast_height: tip,
+        fee_bumps: Vec::new(),
+        broadcast_attempts: 1,
+        last_broadcast_at: 0,
+        confirmed_height: None,
+        created_at: 0,
+        updated_at: 0,
+    })?;
+    Ok(txid)
+}
+
+/// Broadcasts a signed RBF replacement of sweep `sweep_id` and records it in
+/// its place. Returns the new txid.
+pub async fn broadcast_replacement(
+    db: &Db,
+    sweep_id: &str,
+    tx: &Transaction,
+    fee: u64,
+    fee_rate: u64,
+) -> Result<String> {
+    let raw_hex = hex::encode(tx.serialize());
+    let tip = broadcast_tip(db).await?;
+    rpc_async(
+        "blockchain.transaction.broadcast",
+        &[raw_hex.clone().into()],
+    )
+    .await?;
+    let txid = tx.txid().to_string();
+    db.replace_sweep(sweep_id, &txid, &raw_hex, fee, fee_rate, tip)?;
+    Ok(txid)
+}
+
+/// Tip height a broadcast is recorded at; the last one seen if the server
+/// cannot be asked.
+async fn broadcast_tip(db: &Db) -> Result<u64> {
+    Ok(match rpc_async("blockchain.headers.subscribe", &[]).await {
+        Ok(hdr) => hdr["height"].as_u64().unwrap_or(0),
+        Err(_) => db.chain_tip()?.unwrap_or(0),
+    })
+}
+
+/// Follows every unconfirmed sweep: marks it confirmed once it has
+/// `SWEEP_CONFIRMATIONS`, fee-bumps it if it sat in the mempool for
+/// `SWEEP_RBF_AFTER_BLOCKS`, rebroadcasts it if it fell out of the mempool,
+/// and after `SWEEP_MAX_BROADCASTS` failed attempts abandons it so its
+/// payments are rebuilt into a fresh sweep. A sweep whose history cannot be
+/// fetched is skipped until the next round.
+pub async fn track_sweeps(db: &Db, policies: &Policies) -> Result<()> {
+    let needed = env::var("SWEEP_CONFIRMATIONS")
+        .unwrap_or_else(|_| "1".into())
+        .parse::<u64>()
+        .unwrap_or(1)
+        .max(1);
+    let max_broadcasts = env::var("SWEEP_MAX_BROADCASTS")
+        .unwrap_or_else(|_| "5".into())
+        .parse::<u32>()
+        .unwrap_or(5);
+
+    let policy = BumpPolicy::from_env();
+
+    let sweeps = db.active_sweeps()?;
+    if sweeps.is_empty() {
+        return Ok(());
+    }
+    let hdr = rpc_async("blockchain.headers.subscribe", &[]).await?;
+    let tip = hdr["height"].as_u64().unwrap_or(0);
+
+    let mut rebuild = Vec::new();
+    for sw in sweeps {
+        let Some(first) = sw.inputs.first() else {
+            continue;
+        };
+        let hist = match rpc_async(
+            "blockchain.scripthash.get_history",
+            &[script_hash(&first.address).into()],
+        )
+        .await
+        {
+            Ok(hist) => hist,
+            Err(e) => {
+                warn!(txid = %sw.txid, error = %e, "sweep history unavailable");
+                continue;
+            }
+        };
+        let height_of = |txid: &str| {
+            hist.as_array().and_then(|h| {
+                h.iter()
+                    .find(|e| e["tx_hash"].as_str() == Some(txid))
+                    .map(|e| e["height"].as_i64().unwrap_or(0))
+            })
+        };
+        // a transaction this one replaced may still be mined instead of it
+        let mined = std::iter::once(sw.txid.as_str())
+            .chain(sw.fee_bumps.iter().map(|b| b.txid.as_str()))
+            .find_map(|txid| height_of(txid).filter(|h| *h > 0).map(|h| (txid, h as u64)));
+        if let Some((txid, h)) = mined {
+            if tip + 1 >= h + needed {
+                let swept = db.confirm_sweep(&sw.id, txid, h)?;
+                info!(%txid, height = h, "sweep confirmed");
+                if txid != sw.txid {
+                    info!(%txid, replacement = %sw.txid, "replaced sweep transaction confirmed");
+                }
+                debug!(%txid, payments = swept.len(), "payments swept");
+                for id in &swept {
+                    live::publish(PaymentUpdate::status(id, PaymentStatus::Swept));
+                }
+            }
+            continue;
+        }
+
+        match height_of(&sw.txid) {
+            Some(_) if tip >= sw.broadcast_height + policy.after_blocks => {
+                if let Err(e) = bump_fee(db, &sw, &policy).await {
+                    warn!(txid = %sw.txid, error = %e, "fee bump failed");
+                }
+            }
+            // still in the mempool, give it time
+            Some(_) => {}
+            None => {
+                if sw.broadcast_attempts >= max_broadcasts {
+                    warn!(txid = %sw.txid, attempts = sw.broadcast_attempts, "sweep dropped, rebuilding");
+                    db.fail_sweep(&sw.id)?;
+                    rebuild.extend(sw.payment_ids);
+                    continue;
+                }
+                db.note_rebroadcast(&sw.id)?;
+                match rpc_async(
+                    "blockchain.transaction.broadcast",
+                    &[sw.raw_hex.clone().into()],
+                )
+                .await
+                {
+                    Ok(_) => info!(txid = %sw.txid, "rebroadcast sweep"),
+                    Err(e) => warn!(txid = %sw.txid, error = %e, "rebroadcast rejected"),
+                }
+            }
+        }
+    }
+
+    let mut ready = Vec::new();
+    for id in rebuild {
+        if let Ok(Some(p)) = db.find(&id) {
+            if let Some(r) = process(db, &p, policies).await? {
+                ready.push(r);
+            }
+        }
+    }
+    if !ready.is_empty() {
+        sweep_ready(db, ready).await?;
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// When and how far to replace-by-fee a sweep stuck in the mempool.
+struct BumpPolicy {
+    after_blocks: u64,
+    multiplier: f64,
+    /// litoshis per vbyte
+    max_fee_rate: u64,
+}
+
+impl BumpPolicy {
+    fn from_env() -> Self {
+        let after_blocks = env::var("SWEEP_RBF_AFTER_BLOCKS")
+            .unwrap_or_else(|_| "3".into())
+            .parse::<u64>()
+            .unwrap_or(3)
+            .max(1);
+        let multiplier = env::var("SWEEP_RBF_MULTIPLIER")
+            .unwrap_or_else(|_| "1.5".into())
+            .parse::<f64>()
+            .unwrap_or(1.5);
+        let max_fee_rate = env::var("SWEEP_MAX_FEE_RATE")
+            .unwrap_or_else(|_| "200".into())
+            .parse::<u64>()
+            .unwrap_or(200);
+        Self {
+            after_blocks,
+            multiplier,
+            max_fee_rate,
+        }
+    }
+}
+
+/// Rebuilds `sw` spending the same inputs at a higher feerate and replaces it
+/// on the network. The old txid is kept in the sweep's fee bump history.
+async fn bump_fee(db: &Db, sw: &Sweep, policy: &BumpPolicy) -> Result<()> {
+    let mut payments: Vec<Payment> = Vec::new();
+    for id in &sw.payment_ids {
+        payments.extend(db.find(id)?);
+    }
+    // externally signed: the replacement goes out as a PSBT too
+    let export = payments
+        .iter()
+        .any(|p| p.derivation_index.is_some() && is_watch_only());
+    if export && db.has_replacement_psbt(&sw.id)? {
+        return Ok(());
+    }
+
+    let estimate = fee_rate_async().await;
+    let bumped = ((sw.fee_rate as f64) * policy.multiplier).ceil() as u64;
+    // BIP125 needs at least the incremental relay fee (1 lit/vB) on top
+    let fee_rate = bumped
+        .max(sw.fee_rate + 1)
+        .max(estimate)
+        .min(policy.max_fee_rate);
+    if fee_rate <= sw.fee_rate {
+        warn!(txid = %sw.txid, fee_rate = sw.fee_rate, "sweep stuck at SWEEP_MAX_FEE_RATE");
+        return Ok(());
+    }
+
+    // same destination as the transaction being replaced
+    let replaced: Transaction = deserialize(&hex::decode(&sw.raw_hex)?)?;
+    let destination = replaced
+        .output
+        .first()
+        .ok_or_else(|| anyhow!("sweep {} has no output", sw.id))?
+        .script_pubkey
+        .clone();
+    let mut tx = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: Vec::with_capacity(sw.inputs.len()),
+        output: vec![TxOut {
+            value: 0,
+            script_pubkey: destination,
+        }],
+    };
+    let mut owners = Vec::with_capacity(sw.inputs.len());
+    for i in &sw.inputs {
+        let p = payments
+            .iter()
+            .find(|p| p.id == i.payment_id)
+            .ok_or_else(|| anyhow!("payment {} of sweep {} missing", i.payment_id, sw.id))?;
+        tx.input.push(TxIn {
+            previous_output: OutPoint::new(Txid::from_str(&i.txid)?, i.vout),
+            script_sig: Script::new(),
+            sequence: RBF_SEQUENCE,
+            witness: Witness::default(),
+        });
+        owners.push((p, i.value));
+    }
+    let total: u64 = sw.inputs.iter().map(|i| i.value).sum();
+    let fee = estimated_vsize(&tx) * fee_rate;
+    if total <= fee {
+        warn!(txid = %sw.txid, fee_rate, "bumped fee would consume the whole sweep");
+        return Ok(());
+    }
+    tx.output[0].value = total - fee;
+
+    if export {
+        let record = psbt::export(db, tx, &owners, Some(&sw.id))?;
+        warn!(txid = %sw.txid, psbt_id = %record.id, old_fee_rate = sw.fee_rate, fee_rate,
+            "sweep stuck, exported a fee bump for signing");
+        return Ok(());
+    }
+    let spends = owners
+        .iter()
+        .map(|(p, v)| Ok((signing_key(p)?, *v)))
+        .collect::<Result<Vec<_>>>()?;
+    sign_inputs(&mut tx, &spends)?;
+
+    let txid = broadcast_replacement(db, &sw.id, &tx, fee, fee_rate).await?;
+    info!(old_txid = %sw.txid, %txid, old_fee_rate = sw.fee_rate, fee_rate, "replaced sweep");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    #[test]
+    fn underpayment_topped_up_after_expiry_is_paid_late() {
+        let db = Db::memory();
+        let now = now();
+        db.insert_test_payment("p", PaymentStatus::Underpaid, now - 10);
+        // the underpayment was mined well before the deadline
+        let outputs = [("a".to_string(), 0, 60_000), ("b".to_string(), 0, 40_000)];
+        let underpayment = ("a".to_string(), 100, Some(now - 100));
+        db.record_payment_txs("p", &outputs[..1], std::slice::from_ref(&underpayment))
+            .unwrap();
+        assert_eq!(db.expire_overdue(now, 0).unwrap(), ["p"]);
+        let p = db.find("p").unwrap().unwrap();
+        assert_eq!(p.status, PaymentStatus::Expired);
+        assert!(!paid_late(&p, &db.payment_txs("p").unwrap()));
+        // on its own it is held for review rather than stranded
+        assert_eq!(
+            expired_outcome(
+                &p,
+                &db.payment_txs("p").unwrap(),
+                PaymentStatus::Underpaid,
+                LateAction::Accept
+            ),
+            (PaymentStatus::PaidLate, Some(LateAction::Review))
+        );
+
+        // the top-up is seen after the deadline, then mined
+        let top_up = ("b".to_string(), 0, None);
+        db.record_payment_txs("p", &outputs, &[underpayment.clone(), top_up])
+            .unwrap();
+        assert!(!paid_late(&p, &db.payment_txs("p").unwrap()));
+        let top_up = ("b".to_string(), 101, Some(now));
+        db.record_payment_txs("p", &outputs, &[underpayment, top_up])
+            .unwrap();
+        let txs = db.payment_txs("p").unwrap();
+        assert!(paid_late(&p, &txs));
+        assert_eq!(
+            expired_outcome(&p, &txs, PaymentStatus::Paid, LateAction::Accept),
+            (PaymentStatus::PaidLate, Some(LateAction::Accept))
+        );
+    }
+
+    #[test]
+    fn lateness_follows_block_time_not_sync_time() {
+        let db = Db::memory();
+        let now = now();
+        db.insert_test_payment("p", PaymentStatus::Expired, now - 3600);
+        // mined before the deadline, first synced an hour later
+        db.record_payment_txs(
+            "p",
+            &[("a".to_string(), 0, 100_000)],
+            &[("a".to_string(), 100, Some(now - 3660))],
+        )
+        .unwrap();
+        let p = db.find("p").unwrap().unwrap();
+        let txs = db.payment_txs("p").unwrap();
+        assert!(txs[0].confirmed_at.unwrap() > p.expires_at);
+        assert!(!paid_late(&p, &txs));
+    }
+
+    #[test]
+    fn confirming_payment_settles_past_its_deadline() {
+        let db = Db::memory();
+        let now = now();
+        db.insert_test_payment("c", PaymentStatus::Confirming, now - 10);
+        db.record_payment_txs(
+            "c",
+            &[("a".to_string(), 0, 100_000)],
+            &[("a".to_string(), 100, Some(now - 60))],
+        )
+        .unwrap();
+        // still short of CONFIRMATIONS at the deadline: it does not expire
+        assert!(db.expire_overdue(now, 0).unwrap().is_empty());
+        // confirmations reached
+        assert!(db.set_status("c", PaymentStatus::Paid).unwrap());
+
+        // one that expired while confirming still settles once they are reached
+        db.insert_test_payment("e", PaymentStatus::Expired, now - 10);
+        db.record_payment_txs(
+            "e",
+            &[("b".to_string(), 0, 100_000)],
+            &[("b".to_string(), 100, Some(now - 60))],
+        )
+        .unwrap();
+        let p = db.find("e").unwrap().unwrap();
+        let (status, action) = expired_outcome(
+            &p,
+            &db.payment_txs("e").unwrap(),
+            PaymentStatus::Paid,
+            LateAction::Review,
+        );
+        assert_eq!((status, action), (PaymentStatus::Paid, None));
+        assert!(db.set_status("e", status).unwrap());
+    }
+
+    fn settlement(under: u64, under_pct: f64, over: u64, over_pct: f64) -> SettlementPolicy {
+        SettlementPolicy {
+            under_tolerance: under,
+            under_tolerance_pct: under_pct,
+            over_tolerance: over,
+            over_tolerance_pct: over_pct,
+            accept_within_tolerance: true,
+            underpaid_grace: 0,
+        }
+    }
+
+    #[test]
+    fn settlement_outcomes() {
+        use PaymentStatus::{Overpaid, Paid, Underpaid};
+        const LTC: u64 = 100_000_000;
+        let cases = [
+            // (policy, amount, confirmed, expected)
+            (settlement(0, 0.0, 0, 0.0), LTC, LTC, Paid),
+            (settlement(0, 0.0, 0, 0.0), LTC, LTC - 1, Underpaid),
+            (settlement(0, 0.0, 0, 0.0), LTC, LTC + 1, Overpaid),
+            (settlement(0, 0.0, 0, 0.0), LTC, 0, Underpaid),
+            // absolute tolerance, boundary inclusive
+            (
+                settlement(1_000_000, 0.0, 0, 0.0),
+                LTC,
+                LTC - 1_000_000,
+                Paid,
+            ),
+            (
+                settlement(1_000_000, 0.0, 0, 0.0),
+                LTC,
+                LTC - 1_000_001,
+                Underpaid,
+            ),
+            (settlement(0, 0.0, 500_000, 0.0), LTC, LTC + 500_000, Paid),
+            (
+                settlement(0, 0.0, 500_000, 0.0),
+                LTC,
+                LTC + 500_001,
+                Overpaid,
+            ),
+            // percentage tolerance, boundary inclusive
+            (settlement(0, 2.0, 0, 0.0), LTC, LTC - 2_000_000, Paid),
+            (settlement(0, 2.0, 0, 0.0), LTC, LTC - 2_000_001, Underpaid),
+            (settlement(0, 0.0, 0, 1.0), LTC, LTC + 1_000_000, Paid),
+            (settlement(0, 0.0, 0, 1.0), LTC, LTC + 1_000_001, Overpaid),
+            // the larger of the two applies
+            (
+                settlement(1_000_000, 2.0, 0, 0.0),
+                LTC,
+                LTC - 2_000_000,
+                Paid,
+            ),
+            (
+                settlement(3_000_000, 2.0, 0, 0.0),
+                LTC,
+                LTC - 3_000_000,
+                Paid,
+            ),
+            (
+                settlement(3_000_000, 2.0, 0, 0.0),
+                LTC,
+                LTC - 3_000_001,
+                Underpaid,
+            ),
+            (settlement(0, 0.0, 100, 1.0), 1_000, 1_100, Paid),
+            (settlement(0, 0.0, 100, 1.0), 1_000, 1_101, Overpaid),
+            // a fractional percentage rounds down to whole litoshis
+            (settlement(0, 1.0, 0, 0.0), 150, 149, Paid),
+            (settlement(0, 1.0, 0, 0.0), 150, 148, Underpaid),
+            // under and over tolerances are independent
+            (settlement(1_000_000, 0.0, 0, 0.0), LTC, LTC + 1, Overpaid),
+            (settlement(0, 0.0, 1_000_000, 0.0), LTC, LTC - 1, Underpaid),
+        ];
+        for (i, (policy, amount, confirmed, expected)) in cases.iter().enumerate() {
+            assert_eq!(policy.outcome(*amount, *confirmed), *expected, "case {i}");
+        }
+    }
+
+    #[test]
+    fn tolerances_ignored_unless_accepted() {
+        let policy = SettlementPolicy {
+            accept_within_tolerance: false,
+            ..settlement(1_000_000, 5.0, 1_000_000, 5.0)
+        };
+        assert_eq!(policy.outcome(100_000, 100_000), PaymentStatus::Paid);
+        assert_eq!(policy.outcome(100_000, 99_999), PaymentStatus::Underpaid);
+        assert_eq!(policy.outcome(100_000, 100_001), PaymentStatus::Overpaid);
+    }
+}
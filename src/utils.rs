@@ -1,5 +1,5 @@
 use aes_gcm::{
-    aead::{Aead, KeyInit, OsRng},
+    aead::{Aead, KeyInit},
     Aes256Gcm, Key, Nonce,
 };
 use bech32::{decode, encode, FromBase32, ToBase32, Variant};
@@ -7,76 +7,243 @@ use bitcoin::blockdata::script::Script;
 use bitcoin::util::address::WitnessVersion;
 use hex::{decode as hex_decode, encode as hex_encode};
 use lazy_static::lazy_static;
-use rand::RngCore;
 use ripemd::Ripemd160;
-use secp256k1::{Secp256k1, SecretKey};
+use serde::{Deserialize, Deserializer};
 use sha2::{Digest, Sha256};
 use std::env;
-use tracing::{debug, info, instrument, trace};
+use tracing::{debug, instrument, trace};
 
 lazy_static! {
-    static ref AES: Aes256Gcm = {
-        let key_hex = env::var("AES_KEY").expect("AES_KEY env missing");
+    /// `None` when `AES_KEY` is unset, e.g. a deployment without legacy payments.
+    static ref AES: Option<Aes256Gcm> = env::var("AES_KEY").ok().map(|key_hex| {
         let key_bytes = hex_decode(&key_hex).expect("AES_KEY hex decode");
         assert_eq!(key_bytes.len(), 32, "AES_KEY len != 32 bytes");
         debug!("AES key init");
         Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key_bytes))
-    };
-}
-
-#[instrument(level = "debug", skip(wif))]
-pub fn encrypt_wif(wif: &str) -> String {
-    trace!("Encrypting WIF");
-    let mut iv = [0u8; 12];
-    OsRng.fill_bytes(&mut iv);
-    let nonce = Nonce::from_slice(&iv);
-    let mut ct = AES.encrypt(nonce, wif.as_bytes()).expect("encrypt");
-    let tag = ct.split_off(ct.len() - 16);
-    hex_encode([iv.to_vec(), tag, ct].concat())
+    });
 }
 
 #[instrument(level = "debug", skip(h))]
 pub fn decrypt_wif(h: &str) -> String {
     trace!("Decrypting WIF");
+    decrypt_secret(h).expect("AES_KEY env missing")
+}
+
+/// Encrypts `plain` with `AES_KEY` as hex `iv || tag || ciphertext`, the
+/// layout of stored WIFs; `None` without a key.
+pub fn encrypt_secret(plain: &str) -> Option<String> {
+    let aes = AES.as_ref()?;
+    let iv: [u8; 12] = rand::random();
+    let mut ct = aes
+        .encrypt(Nonce::from_slice(&iv), plain.as_bytes())
+        .expect("encrypt");
+    let tag = ct.split_off(ct.len() - 16);
+    Some(hex_encode([&iv[..], &tag, &ct].concat()))
+}
+
+/// Reverses [`encrypt_secret`]; `None` without a key.
+pub fn decrypt_secret(h: &str) -> Option<String> {
+    let aes = AES.as_ref()?;
     let buf = hex_decode(h).expect("hex decode");
     let (iv, rest) = buf.split_at(12);
     let (tag, ct) = rest.split_at(16);
     let nonce = Nonce::from_slice(iv);
     let mut data = ct.to_vec();
     data.extend_from_slice(tag);
-    let dec = AES.decrypt(nonce, data.as_ref()).expect("decrypt");
-    String::from_utf8(dec).expect("utf8")
+    let dec = aes.decrypt(nonce, data.as_ref()).expect("decrypt");
+    Some(String::from_utf8(dec).expect("utf8"))
+}
+
+/// Base units (litoshis) per LTC.
+pub const LITOSHIS_PER_LTC: u64 = 100_000_000;
+
+/// Parses a decimal LTC amount such as `"0.29"` into litoshis exactly, without
+/// going through floating point. More than 8 decimals is an error.
+pub fn parse_ltc(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (whole, frac) = s.split_once('.').unwrap_or((s, ""));
+    if (whole.is_empty() && frac.is_empty()) || frac.len() > 8 {
+        return None;
+    }
+    if !whole
+        .bytes()
+        .chain(frac.bytes())
+        .all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+    let whole: u64 = if whole.is_empty() {
+        0
+    } else {
+        whole.parse().ok()?
+    };
+    let frac: u64 = if frac.is_empty() {
+        0
+    } else {
+        format!("{frac:0<8}").parse().ok()?
+    };
+    whole.checked_mul(LITOSHIS_PER_LTC)?.checked_add(frac)
+}
+
+/// Formats litoshis as an LTC decimal string with all 8 places, e.g. `"0.29000000"`.
+pub fn format_ltc(litoshis: u64) -> String {
+    format!(
+        "{}.{:08}",
+        litoshis / LITOSHIS_PER_LTC,
+        litoshis % LITOSHIS_PER_LTC
+    )
+}
+
+/// `format_ltc` with a leading `-` for negative amounts.
+pub fn format_ltc_signed(litoshis: i64) -> String {
+    let sign = if litoshis < 0 { "-" } else { "" };
+    format!("{sign}{}", format_ltc(litoshis.unsigned_abs()))
+}
+
+/// Deserializes an LTC amount given as a decimal string (preferred) or a JSON
+/// number into litoshis. Numbers are rounded to 8 decimals.
+pub fn deserialize_ltc<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Amount {
+        Text(String),
+        Number(f64),
+    }
+    let parsed = match Amount::deserialize(d)? {
+        Amount::Text(s) => parse_ltc(&s),
+        Amount::Number(n) if n.is_finite() && n >= 0.0 => parse_ltc(&format!("{n:.8}")),
+        Amount::Number(_) => None,
+    };
+    parsed.ok_or_else(|| serde::de::Error::custom("invalid LTC amount"))
 }
 
 fn hash160(b: &[u8]) -> [u8; 20] {
     let mut out = [0u8; 20];
-    out.copy_from_slice(&Ripemd160::digest(&Sha256::digest(b)));
+    out.copy_from_slice(&Ripemd160::digest(Sha256::digest(b)));
     out
 }
 
-#[instrument(level = "info")]
-pub fn new_key() -> (SecretKey, String, String) {
-    info!("Generating key");
-    let secp = Secp256k1::new();
-    let sk = SecretKey::new(&mut rand::thread_rng());
-    let pk = secp256k1::PublicKey::from_secret_key(&secp, &sk);
-    let prog = hash160(&pk.serialize());
-    let mut data = vec![(&[0u8]).to_base32()[0]];
+#[instrument(level = "debug", skip(pubkey))]
+pub fn p2wpkh_address(pubkey: &[u8]) -> String {
+    let prog = hash160(pubkey);
+    let mut data = vec![[0u8].to_base32()[0]];
     data.extend_from_slice(&prog.to_base32());
     let addr = encode("ltc", data, Variant::Bech32).expect("bech32");
     debug!("addr {}", addr);
-    (sk, sk.display_secret().to_string(), addr)
+    addr
 }
 
 #[instrument(level = "debug", skip(addr))]
-pub fn script_hash(addr: &str) -> String {
-    trace!("Script hash");
+pub fn script_pubkey(addr: &str) -> Script {
     let (_, data, _) = decode(addr).expect("decode");
     let (ver, prog5) = data.split_first().expect("empty");
-    assert_eq!(*ver, (&[0u8]).to_base32()[0]);
+    assert_eq!(*ver, [0u8].to_base32()[0]);
     let prog: Vec<u8> = Vec::<u8>::from_base32(prog5).expect("base32");
-    let script = Script::new_witness_program(WitnessVersion::V0, &prog);
+    Script::new_witness_program(WitnessVersion::V0, &prog)
+}
+
+/// Whether `addr` is a Litecoin mainnet segwit v0 address (P2WPKH or P2WSH),
+/// the only kind [`script_pubkey`] handles.
+pub fn is_segwit_v0_address(addr: &str) -> bool {
+    let Ok((hrp, data, Variant::Bech32)) = decode(addr) else {
+        return false;
+    };
+    let Some((ver, prog5)) = data.split_first() else {
+        return false;
+    };
+    hrp == "ltc"
+        && *ver == [0u8].to_base32()[0]
+        && Vec::<u8>::from_base32(prog5).is_ok_and(|p| p.len() == 20 || p.len() == 32)
+}
+
+#[instrument(level = "debug", skip(addr))]
+pub fn script_hash(addr: &str) -> String {
+    trace!("Script hash");
+    let script = script_pubkey(addr);
     let mut h = Sha256::digest(script.as_bytes()).to_vec();
     h.reverse();
     hex_encode(h)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Body {
+        #[serde(deserialize_with = "deserialize_ltc")]
+        amount: u64,
+    }
+
+    fn amount(json: &str) -> Option<u64> {
+        serde_json::from_str::<Body>(json).ok().map(|b| b.amount)
+    }
+
+    #[test]
+    fn parses_decimal_amounts_exactly() {
+        assert_eq!(parse_ltc("0.29"), Some(29_000_000));
+        assert_eq!(parse_ltc("1"), Some(LITOSHIS_PER_LTC));
+        assert_eq!(parse_ltc(" 1.5 "), Some(150_000_000));
+        assert_eq!(parse_ltc(".00000001"), Some(1));
+        assert_eq!(parse_ltc("2."), Some(200_000_000));
+        assert_eq!(parse_ltc("0.12345678"), Some(12_345_678));
+    }
+
+    #[test]
+    fn rejects_invalid_amounts() {
+        for s in [
+            "",
+            ".",
+            "0.123456789",
+            "-1",
+            "-0.5",
+            "+1",
+            "1e3",
+            "1.2.3",
+            "abc",
+            "1 000",
+        ] {
+            assert_eq!(parse_ltc(s), None, "{s:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_overflowing_amounts() {
+        let max_whole = u64::MAX / LITOSHIS_PER_LTC;
+        assert!(parse_ltc(&max_whole.to_string()).is_some());
+        assert_eq!(parse_ltc(&(max_whole + 1).to_string()), None);
+        assert_eq!(parse_ltc("99999999999999999999999"), None);
+    }
+
+    #[test]
+    fn deserializes_strings_and_numbers() {
+        assert_eq!(amount(r#"{"amount":"0.29"}"#), Some(29_000_000));
+        assert_eq!(amount(r#"{"amount":0.29}"#), Some(29_000_000));
+        assert_eq!(amount(r#"{"amount":3}"#), Some(300_000_000));
+        assert_eq!(amount(r#"{"amount":0.000000014}"#), Some(1));
+        assert_eq!(amount(r#"{"amount":-0.1}"#), None);
+        assert_eq!(amount(r#"{"amount":"0.123456789"}"#), None);
+        assert_eq!(amount(r#"{"amount":null}"#), None);
+    }
+
+    #[test]
+    fn formats_round_trip() {
+        assert_eq!(format_ltc(29_000_000), "0.29000000");
+        assert_eq!(format_ltc(0), "0.00000000");
+        assert_eq!(format_ltc_signed(-1), "-0.00000001");
+        assert_eq!(
+            format_ltc_signed(i64::MIN),
+            format!("-{}", format_ltc(1 << 63))
+        );
+        for v in [
+            0,
+            1,
+            29_000_000,
+            LITOSHIS_PER_LTC,
+            123_456_789_012,
+            u64::MAX,
+        ] {
+            assert_eq!(parse_ltc(&format_ltc(v)), Some(v), "{v}");
+        }
+    }
+}
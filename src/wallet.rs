@@ -0,0 +1,149 @@
+use crate::utils::p2wpkh_address;
+use anyhow::{anyhow, bail, Context, Result};
+use bitcoin::{
+    secp256k1::{All, Secp256k1},
+    util::{
+        base58,
+        bip32::{
+            ChildNumber, DerivationPath, ExtendedPrivKey, ExtendedPubKey, Fingerprint, KeySource,
+        },
+    },
+};
+use once_cell::sync::Lazy;
+use std::{env, str::FromStr};
+use tracing::{error, info};
+
+/// BIP32 mainnet version bytes; Litecoin-specific prefixes (Ltpv/Ltub, zprv/zpub)
+/// are rewritten to these before decoding since only the payload matters.
+const XPRV_VERSION: [u8; 4] = [0x04, 0x88, 0xAD, 0xE4];
+const XPUB_VERSION: [u8; 4] = [0x04, 0x88, 0xB2, 0x1E];
+
+/// External (receive) chain of a BIP84 account: `m/84'/2'/0'/0/i`.
+const RECEIVE_CHAIN: u32 = 0;
+
+/// The BIP84 account deposit addresses are derived from.
+pub enum Account {
+    Signing(ExtendedPrivKey),
+    WatchOnly(ExtendedPubKey),
+}
+
+static SECP: Lazy<Secp256k1<All>> = Lazy::new(Secp256k1::new);
+
+static ACCOUNT: Lazy<Account> = Lazy::new(|| match Account::from_env() {
+    Ok(acct) => {
+        info!(
+            watch_only = matches!(acct, Account::WatchOnly(_)),
+            "Loaded wallet account from environment"
+        );
+        acct
+    }
+    Err(e) => {
+        error!("Failed to load wallet account: {:#}", e);
+        panic!("WALLET_XPRV or WALLET_XPUB environment variable is required");
+    }
+});
+
+fn decode_extended(s: &str, version: [u8; 4]) -> Result<Vec<u8>> {
+    let mut data = base58::from_check(s.trim()).context("base58 decode")?;
+    if data.len() != 78 {
+        bail!("extended key must be 78 bytes, got {}", data.len());
+    }
+    data[0..4].copy_from_slice(&version);
+    Ok(data)
+}
+
+impl Account {
+    pub fn from_env() -> Result<Self> {
+        if let Some(xprv) = env::var("WALLET_XPRV").ok().filter(|v| !v.is_empty()) {
+            let data = decode_extended(&xprv, XPRV_VERSION).context("WALLET_XPRV")?;
+            return Ok(Self::Signing(ExtendedPrivKey::decode(&data)?));
+        }
+        if let Some(xpub) = env::var("WALLET_XPUB").ok().filter(|v| !v.is_empty()) {
+            let data = decode_extended(&xpub, XPUB_VERSION).context("WALLET_XPUB")?;
+            return Ok(Self::WatchOnly(ExtendedPubKey::decode(&data)?));
+        }
+        bail!("neither WALLET_XPRV nor WALLET_XPUB is set")
+    }
+
+    pub fn xpub(&self) -> ExtendedPubKey {
+        match self {
+            Self::Signing(xprv) => ExtendedPubKey::from_priv(&SECP, xprv),
+            Self::WatchOnly(xpub) => *xpub,
+        }
+    }
+}
+
+fn receive_path(index: u32) -> Result<[ChildNumber; 2]> {
+    Ok([
+        ChildNumber::from_normal_idx(RECEIVE_CHAIN)?,
+        ChildNumber::from_normal_idx(index)?,
+    ])
+}
+
+/// Origin of the account key as seen by an external signer: `WALLET_FINGERPRINT`
+/// (master key) plus `WALLET_ACCOUNT_PATH`, or the account key itself at `m`
+/// when no fingerprint is configured.
+static ACCOUNT_ORIGIN: Lazy<KeySource> = Lazy::new(|| {
+    let fingerprint = env::var("WALLET_FINGERPRINT")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .and_then(|v| hex::decode(v.trim()).ok())
+        .filter(|b| b.len() == 4);
+    match fingerprint {
+        Some(fp) => {
+            let path = env::var("WALLET_ACCOUNT_PATH").unwrap_or_else(|_| "m/84'/2'/0'".into());
+            let path = DerivationPath::from_str(&path).expect("WALLET_ACCOUNT_PATH");
+            (Fingerprint::from(&fp[..]), path)
+        }
+        None => (ACCOUNT.xpub().fingerprint(), DerivationPath::master()),
+    }
+});
+
+pub fn account_xpub() -> ExtendedPubKey {
+    ACCOUNT.xpub()
+}
+
+pub fn account_origin() -> KeySource {
+    ACCOUNT_ORIGIN.clone()
+}
+
+/// Full key origin of receive index `index`, for PSBT `bip32_derivation`.
+pub fn key_source(index: u32) -> Result<KeySource> {
+    let (fingerprint, path) = account_origin();
+    Ok((fingerprint, path.extend(receive_path(index)?)))
+}
+
+pub fn is_watch_only() -> bool {
+    matches!(*ACCOUNT, Account::WatchOnly(_))
+}
+
+/// Public key of receive address `index`, compressed SEC1.
+pub fn derive_pubkey(index: u32) -> Result<[u8; 33]> {
+    let child = ACCOUNT.xpub().derive_pub(&SECP, &receive_path(index)?)?;
+    Ok(child.public_key.serialize())
+}
+
+/// Bech32 P2WPKH deposit address for receive index `index`.
+pub fn derive_address(index: u32) -> Result<String> {
+    Ok(p2wpkh_address(&derive_pubkey(index)?))
+}
+
+/// Receive address `index` of a merchant's payout account `xpub` (BIP84,
+/// `<xpub>/0/index`).
+pub fn payout_address(xpub: &str, index: u32) -> Result<String> {
+    let data = decode_extended(xpub, XPUB_VERSION).context("payout xpub")?;
+    let xpub = ExtendedPubKey::decode(&data)?;
+    let child = xpub.derive_pub(&SECP, &receive_path(index)?)?;
+    Ok(p2wpkh_address(&child.public_key.serialize()))
+}
+
+/// Signing key for receive index `index`; fails in watch-only mode.
+pub fn derive_secret(index: u32) -> Result<secp256k1::SecretKey> {
+    let Account::Signing(xprv) = &*ACCOUNT else {
+        return Err(anyhow!("wallet is watch-only, cannot derive private keys"));
+    };
+    let child = xprv.derive_priv(&SECP, &receive_path(index)?)?;
+    Ok(secp256k1::SecretKey::from_slice(
+        &child.private_key.secret_bytes(),
+    )?)
+}
@@ -0,0 +1,230 @@
+use crate::{
+    db::{ChainState, Db, Payment},
+    electrum::{rpc_async, subscription_client},
+    utils::{script_hash, script_pubkey},
+};
+use anyhow::{anyhow, Result};
+use electrum_client::{bitcoin::ScriptBuf, Client, ElectrumApi, ScriptStatus};
+use std::{
+    collections::HashMap,
+    env, thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::{debug, info, warn};
+
+/// Something happened on chain that may change a payment's outcome.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum WatchEvent {
+    /// The payment's script status changed, or a block arrived while it has history.
+    Payment(String),
+    /// The subscription connection was (re)established; anything may have been missed.
+    Resync,
+}
+
+struct Watched {
+    script: ScriptBuf,
+    status: Option<ScriptStatus>,
+}
+
+/// Runs the subscription loop on a dedicated thread. It subscribes to new
+/// headers and to the script hash of every payment awaiting funds (including
+/// recently expired ones, which may still be paid late), and emits a
+/// [`WatchEvent`] only when a status hash changes or a block arrives.
+/// `late_window` is how long after expiry an address stays watched.
+pub fn start(db: Db, tx: UnboundedSender<WatchEvent>, late_window: i64) {
+    let poll = Duration::from_millis(
+        env::var("WATCHER_POLL_MS")
+            .unwrap_or_else(|_| "1000".into())
+            .parse::<u64>()
+            .unwrap_or(1000),
+    );
+    thread::spawn(move || {
+        let mut backoff = 1;
+        loop {
+            match run(&db, &tx, poll, late_window) {
+                Ok(()) => return,
+                Err(e) => {
+                    warn!(error = %e, backoff, "watcher connection lost, reconnecting");
+                    thread::sleep(Duration::from_secs(backoff));
+                    backoff = (backoff * 2).min(60);
+                }
+            }
+        }
+    });
+}
+
+/// One connection's lifetime. Returns `Ok` only once the receiver is gone.
+fn run(db: &Db, tx: &UnboundedSender<WatchEvent>, poll: Duration, late_window: i64) -> Result<()> {
+    let (client, url) = subscription_client()?;
+    let tip = client.block_headers_subscribe_raw()?;
+    info!(%url, height = tip.height, "watcher subscribed to headers");
+    db.set_chain_tip(tip.height as u64)?;
+
+    let mut watched: HashMap<String, Watched> = HashMap::new();
+    if tx.send(WatchEvent::Resync).is_err() {
+        return Ok(());
+    }
+
+    loop {
+        sync_subscriptions(db, &client, &mut watched, tx, late_window)?;
+
+        // ping makes the client read the socket, queueing any notifications
+        client.ping()?;
+
+        let mut new_block = false;
+        while let Some(h) = client.block_headers_pop_raw()? {
+            debug!(height = h.height, "new block");
+            db.set_chain_tip(h.height as u64)?;
+            new_block = true;
+        }
+
+        for (id, w) in watched.iter_mut() {
+            let mut changed = false;
+            while let Some(status) = client.script_pop(&w.script)? {
+                changed |= w.status != Some(status);
+                w.status = Some(status);
+            }
+            if changed || (new_block && w.status.is_some()) {
+                tx.send(WatchEvent::Payment(id.clone()))
+                    .map_err(|_| anyhow!("watcher receiver dropped"))?;
+            }
+        }
+
+        thread::sleep(poll);
+    }
+}
+
+/// Subscribes newly created payments and drops the ones no longer waiting
+/// for funds.
+fn sync_subscriptions(
+    db: &Db,
+    client: &Client,
+    watched: &mut HashMap<String, Watched>,
+    tx: &UnboundedSender<WatchEvent>,
+    late_window: i64,
+) -> Result<()> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let pending = db.awaiting_funds(now - late_window)?;
+
+    let keep: std::collections::HashSet<&str> = pending.iter().map(|p| p.id.as_str()).collect();
+    let gone: Vec<String> = watched
+        .keys()
+        .filter(|id| !keep.contains(id.as_str()))
+        .cloned()
+        .collect();
+    for id in gone {
+        if let Some(w) = watched.remove(&id) {
+            let _ = client.script_unsubscribe(&w.script);
+            debug!(payment_id = %id, "unsubscribed");
+        }
+    }
+
+    // nothing at these addresses yet, which is what the DB already says
+    let mut empty = Vec::new();
+    for p in pending {
+        if watched.contains_key(&p.id) {
+            continue;
+        }
+        let script = ScriptBuf::from_bytes(script_pubkey(&p.address).to_bytes());
+        let status = client.script_subscribe(&script)?;
+        debug!(payment_id = %p.id, has_history = status.is_some(), "subscribed");
+        if status.is_some() {
+            tx.send(WatchEvent::Payment(p.id.clone()))
+                .map_err(|_| anyhow!("watcher receiver dropped"))?;
+        } else {
+            empty.push(p.id.clone());
+        }
+        watched.insert(p.id, Watched { script, status });
+    }
+    if !empty.is_empty() {
+        db.touch_synced(&empty)?;
+    }
+    Ok(())
+}
+
+/// Timestamp of the block at `height`, from its 80-byte header.
+async fn block_time(height: u64) -> Result<i64> {
+    let header = rpc_async("blockchain.block.header", &[height.into()]).await?;
+    let header = hex::decode(header.as_str().unwrap_or_default())?;
+    let time = header
+        .get(68..72)
+        .ok_or_else(|| anyhow!("block header {height} too short"))?;
+    Ok(u32::from_le_bytes(time.try_into()?) as i64)
+}
+
+/// Reads payment `p`'s address from Electrum and persists what is there, so
+/// readers never have to ask Electrum themselves. Returns the state and the
+/// chain tip it was read at (0 when the address has no history).
+pub async fn sync(db: &Db, p: &Payment) -> Result<(ChainState, u64)> {
+    let hist = rpc_async(
+        "blockchain.scripthash.get_history",
+        &[script_hash(&p.address).into()],
+    )
+    .await?;
+    let hist = hist.as_array().cloned().unwrap_or_default();
+    let mut state = ChainState::default();
+    let mut tip = 0;
+    let mut outputs = Vec::new();
+    if !hist.is_empty() {
+        let hdr = rpc_async("blockchain.headers.subscribe", &[]).await?;
+        tip = hdr["height"].as_u64().unwrap_or(0);
+        let bal = rpc_async(
+            "blockchain.scripthash.get_balance",
+            &[script_hash(&p.address).into()],
+        )
+        .await?;
+        state.confirmed = bal["confirmed"].as_u64().unwrap_or(0);
+        state.received = state.confirmed + bal["unconfirmed"].as_i64().unwrap_or(0).max(0) as u64;
+        state.confirmed_height = hist
+            .iter()
+            .filter_map(|h| h["height"].as_u64())
+            .filter(|h| *h > 0)
+            .max();
+        // confirmed history comes first, by height, then the mempool
+        state.first_seen_txid = hist[0]["tx_hash"].as_str().map(str::to_string);
+        // spent outputs are gone from here, but were recorded by an earlier sync
+        let unspent = rpc_async(
+            "blockchain.scripthash.listunspent",
+            &[script_hash(&p.address).into()],
+        )
+        .await?;
+        for u in unspent.as_array().into_iter().flatten() {
+            if let (Some(txid), Some(vout), Some(value)) = (
+                u["tx_hash"].as_str(),
+                u["tx_pos"].as_u64(),
+                u["value"].as_u64(),
+            ) {
+                outputs.push((txid.to_string(), vout as u32, value));
+            }
+        }
+        db.set_chain_tip(tip)?;
+    }
+    // block times already recorded, so each block's header is read once
+    let known: HashMap<(String, u64), i64> = db
+        .payment_txs(&p.id)?
+        .into_iter()
+        .filter_map(|t| Some(((t.txid, t.height?), t.block_time?)))
+        .collect();
+    let mut history = Vec::with_capacity(hist.len());
+    for h in &hist {
+        let (Some(txid), Some(height)) = (h["tx_hash"].as_str(), h["height"].as_i64()) else {
+            continue;
+        };
+        let block_time = match known.get(&(txid.to_string(), height as u64)) {
+            Some(t) => Some(*t),
+            None if height > 0 => match block_time(height as u64).await {
+                Ok(t) => Some(t),
+                Err(e) => {
+                    warn!(height, error = %e, "block header unavailable");
+                    None
+                }
+            },
+            None => None,
+        };
+        history.push((txid.to_string(), height, block_time));
+    }
+    db.record_payment_txs(&p.id, &outputs, &history)?;
+    db.save_chain_state(&p.id, &state)?;
+    Ok((state, tip))
+}
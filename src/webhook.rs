@@ -1,62 +1,515 @@
-use crate::db::Payment;
-use anyhow::{anyhow, Context, Result};
+use crate::{
+    db::{Db, DeliveryOutcome, OutboxEvent, Payment, PaymentStatus, PaymentTx, WebhookAttempt},
+    utils::{format_ltc, format_ltc_signed},
+};
+use anyhow::{anyhow, Result};
+use futures_util::future::join_all;
 use hmac::{Hmac, Mac};
 use reqwest::Client;
 use serde_json::json;
 use sha2::Sha256;
-use std::env;
-use tracing::{debug, error, info};
+use std::{
+    collections::HashMap,
+    env, fmt,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{spawn, time::interval};
+use tracing::{debug, error, info, warn};
 
 type HmacSha256 = Hmac<Sha256>;
 
-pub async fn send_completion_webhook(payment: &Payment) -> Result<()> {
-    let webhook_url = env::var("WEBHOOK_URL").context("WEBHOOK_URL env missing")?;
-    if webhook_url.is_empty() {
-        debug!("WEBHOOK_URL is empty, skipping webhook");
-        return Ok(());
+/// What happened to a payment; the `event` field of every webhook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebhookEvent {
+    /// Invoice issued by `POST /payments`
+    PaymentCreated,
+    /// First funds seen, in the mempool or already in a block
+    PaymentDetected,
+    /// `amount`, or an amount within the settlement tolerance of it, reached
+    /// `CONFIRMATIONS`
+    PaymentConfirmed,
+    PaymentUnderpaid,
+    PaymentOverpaid,
+    PaymentExpired,
+    /// Funds confirmed on an expired payment
+    PaymentPaidLate,
+    /// The sweep carrying the funds to `MAIN_ADDRESS` confirmed
+    SweepConfirmed,
+}
+
+impl WebhookEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::PaymentCreated => "payment.created",
+            Self::PaymentDetected => "payment.detected",
+            Self::PaymentConfirmed => "payment.confirmed",
+            Self::PaymentUnderpaid => "payment.underpaid",
+            Self::PaymentOverpaid => "payment.overpaid",
+            Self::PaymentExpired => "payment.expired",
+            Self::PaymentPaidLate => "payment.paid_late",
+            Self::SweepConfirmed => "sweep.confirmed",
+        }
+    }
+
+    /// Event announcing a status change, if the change is one merchants hear about.
+    pub fn for_transition(from: PaymentStatus, to: PaymentStatus) -> Option<Self> {
+        match to {
+            PaymentStatus::SeenInMempool => Some(Self::PaymentDetected),
+            // funds first seen already mined skip the mempool state
+            PaymentStatus::Confirming if from == PaymentStatus::Created => {
+                Some(Self::PaymentDetected)
+            }
+            PaymentStatus::Paid => Some(Self::PaymentConfirmed),
+            PaymentStatus::Underpaid => Some(Self::PaymentUnderpaid),
+            PaymentStatus::Overpaid => Some(Self::PaymentOverpaid),
+            PaymentStatus::Expired => Some(Self::PaymentExpired),
+            PaymentStatus::PaidLate => Some(Self::PaymentPaidLate),
+            PaymentStatus::Swept => Some(Self::SweepConfirmed),
+            PaymentStatus::Created | PaymentStatus::Confirming => None,
+        }
+    }
+}
+
+impl fmt::Display for WebhookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
     }
+}
 
-    let webhook_secret = env::var("WEBHOOK_SECRET").context("WEBHOOK_SECRET env missing")?;
-    
-    let payload = json!({
-        "event": "payment.completed",
+/// Request body of an event; built once, when the event is queued, so every
+/// attempt sends the same bytes.
+pub fn payload(
+    id: &str,
+    event: WebhookEvent,
+    created_at: i64,
+    payment: &Payment,
+    txs: &[PaymentTx],
+) -> serde_json::Value {
+    json!({
+        "id": id,
+        "event": event.as_str(),
+        "created_at": created_at,
         "payment": {
             "id": payment.id,
             "address": payment.address,
-            "amount": payment.amount,
+            "amount": format_ltc(payment.amount),
+            "amount_litoshis": payment.amount,
             "status": payment.status,
+            "sweep_txid": payment.sweep_txid,
             "created_at": payment.created_at,
             "updated_at": payment.updated_at,
             "expires_at": payment.expires_at,
+            "order_id": payment.order_id,
+            "description": payment.description,
+            "metadata": payment.metadata,
+            "merchant_id": payment.merchant_id,
+            "amount_delta": payment.amount_delta.map(format_ltc_signed),
+            "amount_delta_litoshis": payment.amount_delta,
+            "late_action": payment.late_action,
+            "refund_address": payment.refund_address,
+            "transactions": txs.iter().map(PaymentTx::to_json).collect::<Vec<_>>(),
+        }
+    })
+}
+
+/// How often and how long a failing event is retried.
+struct RetryPolicy {
+    max_attempts: u32,
+    /// seconds before the first retry; doubles after every failure
+    base_delay: i64,
+    max_delay: i64,
+}
+
+impl RetryPolicy {
+    fn from_env() -> Self {
+        let var = |name: &str, default: i64| {
+            env::var(name)
+                .unwrap_or_else(|_| default.to_string())
+                .parse::<i64>()
+                .unwrap_or(default)
+        };
+        Self {
+            max_attempts: var("WEBHOOK_MAX_ATTEMPTS", 12).max(1) as u32,
+            base_delay: var("WEBHOOK_RETRY_BASE", 30).max(1),
+            max_delay: var("WEBHOOK_RETRY_MAX", 21600).max(1),
+        }
+    }
+
+    /// Outcome of attempt number `attempt` (1-based) that failed at `now`.
+    fn after_failure(&self, attempt: u32, now: i64) -> DeliveryOutcome {
+        if attempt >= self.max_attempts {
+            return DeliveryOutcome::Dead;
+        }
+        let delay = self
+            .base_delay
+            .saturating_mul(1 << (attempt - 1).min(30))
+            .min(self.max_delay);
+        DeliveryOutcome::Retry(now + delay)
+    }
+}
+
+/// Bytes of the receiver's response kept per attempt.
+const RESPONSE_SNIPPET_LEN: usize = 512;
+
+/// Delivers the webhook outbox: every second, POSTs each due event to
+/// `WEBHOOK_URL`, merchants side by side, and reschedules failures with
+/// exponential backoff until `WEBHOOK_MAX_ATTEMPTS`, after which the event is
+/// `dead`. Delivery is at-least-once; receivers dedupe on the event `id`.
+pub fn start(db: Db) {
+    spawn(async move {
+        let timeout = env::var("WEBHOOK_TIMEOUT")
+            .unwrap_or_else(|_| "10".into())
+            .parse::<u64>()
+            .unwrap_or(10);
+        let client = Client::builder()
+            .timeout(Duration::from_secs(timeout))
+            .build()
+            .expect("Failed to build webhook HTTP client");
+        let policy = RetryPolicy::from_env();
+        let mut iv = interval(Duration::from_secs(1));
+        loop {
+            iv.tick().await;
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs() as i64;
+            let due = match db.due_webhook_events(now, 50) {
+                Ok(due) => due,
+                Err(e) => {
+                    error!(error = %e, "webhook outbox query failed");
+                    continue;
+                }
+            };
+            // a slow receiver only holds up its own merchant's events, which
+            // still go out in order
+            let mut by_merchant: HashMap<String, Vec<OutboxEvent>> = HashMap::new();
+            for ev in due {
+                by_merchant
+                    .entry(ev.merchant_id.clone())
+                    .or_default()
+                    .push(ev);
+            }
+            join_all(by_merchant.into_iter().map(|(merchant_id, events)| {
+                deliver_merchant(&db, &client, &policy, now, merchant_id, events)
+            }))
+            .await;
         }
     });
-    
-    let payload_str = payload.to_string();
-    
-    let mut mac = HmacSha256::new_from_slice(webhook_secret.as_bytes())
-        .map_err(|_| anyhow!("Invalid webhook secret length"))?;
-    mac.update(payload_str.as_bytes());
-    let signature = hex::encode(mac.finalize().into_bytes());
-    
-    info!(payment_id = %payment.id, "Sending webhook for completed payment");
-    
-    let client = Client::new();
-    let response = client
-        .post(&webhook_url)
+}
+
+/// Delivers one merchant's due events, one after another.
+async fn deliver_merchant(
+    db: &Db,
+    client: &Client,
+    policy: &RetryPolicy,
+    now: i64,
+    merchant_id: String,
+    events: Vec<OutboxEvent>,
+) {
+    let receiver = match receiver(db, &merchant_id) {
+        Ok(r) => r,
+        Err(e) => {
+            error!(%merchant_id, error = %e, "merchant lookup failed");
+            return;
+        }
+    };
+    for ev in events {
+        let Some(receiver) = &receiver else {
+            // stays queued until the merchant configures a receiver
+            if let Err(e) = db.defer_webhook_event(&ev.id, now + RECEIVER_RECHECK_SECS) {
+                error!(event_id = %ev.id, error = %e, "failed to defer webhook event");
+            }
+            continue;
+        };
+        let attempt = deliver(client, receiver, &ev).await;
+        let outcome = if attempt.error.is_none()
+            && attempt.status_code.is_some_and(|c| (200..300).contains(&c))
+        {
+            info!(event_id = %ev.id, event = %ev.event, payment_id = %ev.payment_id,
+                "Webhook sent successfully");
+            DeliveryOutcome::Delivered
+        } else {
+            let outcome = policy.after_failure(ev.attempts + 1, attempt.attempted_at);
+            warn!(event_id = %ev.id, event = %ev.event, payment_id = %ev.payment_id,
+                status = ?attempt.status_code, error = ?attempt.error, ?outcome,
+                "Webhook failed");
+            outcome
+        };
+        if let Err(e) = db.record_webhook_attempt(&attempt, outcome) {
+            error!(event_id = %ev.id, error = %e, "failed to record webhook attempt");
+        }
+    }
+}
+
+/// Seconds between checks for a receiver of a merchant without one.
+const RECEIVER_RECHECK_SECS: i64 = 300;
+
+/// Where a merchant's events are delivered.
+struct Receiver {
+    url: String,
+    /// Signing secrets, or why there are none; a missing secret fails each
+    /// attempt, so it shows up in the delivery log.
+    secrets: Result<Vec<String>, String>,
+}
+
+/// Webhook receiver of merchant `merchant_id`; `None` when it has no URL. The
+/// `default` merchant falls back to `WEBHOOK_URL` and `WEBHOOK_SECRET`.
+fn receiver(db: &Db, merchant_id: &str) -> Result<Option<Receiver>> {
+    let merchant = db
+        .find_merchant(merchant_id)?
+        .ok_or_else(|| anyhow!("merchant {merchant_id} not found"))?;
+    let global = |name: &str| merchant.is_default().then(|| env::var(name).ok()).flatten();
+    let url = merchant
+        .webhook_url
+        .clone()
+        .or_else(|| global("WEBHOOK_URL"))
+        .filter(|u| !u.is_empty());
+    let Some(url) = url else {
+        return Ok(None);
+    };
+    let secrets = merchant
+        .webhook_secret
+        .clone()
+        .or_else(|| global("WEBHOOK_SECRET"))
+        .map(|raw| parse_secrets(&raw))
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| "no webhook secret configured".to_string());
+    Ok(Some(Receiver { url, secrets }))
+}
+
+/// POSTs one event and reports what happened; never fails.
+async fn deliver(client: &Client, receiver: &Receiver, ev: &OutboxEvent) -> WebhookAttempt {
+    let attempted_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let mut attempt = WebhookAttempt {
+        event_id: ev.id.clone(),
+        attempted_at,
+        status_code: None,
+        latency_ms: 0,
+        response: None,
+        error: None,
+    };
+    let secrets = match &receiver.secrets {
+        Ok(s) => s,
+        Err(e) => {
+            attempt.error = Some(e.clone());
+            return attempt;
+        }
+    };
+    let signature = signature_header(secrets, attempted_at, &ev.id, ev.payload.as_bytes());
+    let legacy = env::var("WEBHOOK_LEGACY_SIGNATURE")
+        .unwrap_or_else(|_| "false".into())
+        .parse::<bool>()
+        .unwrap_or(false);
+
+    debug!(event_id = %ev.id, event = %ev.event, "Sending webhook");
+    let started = Instant::now();
+    let mut request = client
+        .post(&receiver.url)
         .header("Content-Type", "application/json")
-        .header("X-Signature", &signature)
-        .body(payload_str)
-        .send()
-        .await
-        .context("Failed to send webhook request")?;
-    
-    if !response.status().is_success() {
-        let status = response.status();
-        let body = response.text().await.unwrap_or_default();
-        error!(payment_id = %payment.id, %status, %body, "Webhook failed");
-        return Err(anyhow!("Webhook failed with status: {}", status));
-    }
-    
-    info!(payment_id = %payment.id, "Webhook sent successfully");
-    Ok(())
-}
\ No newline at end of file
+        .header(EVENT_ID_HEADER, &ev.id)
+        .header(SIGNATURE_HEADER, &signature);
+    if legacy {
+        // body-only HMAC for receivers not yet on SIGNATURE_HEADER; replayable
+        let legacy_signature = hmac(&secrets[0], &[ev.payload.as_bytes()]);
+        request = request.header("X-Signature", hex::encode(legacy_signature));
+    }
+    let result = request.body(ev.payload.clone()).send().await;
+    match result {
+        Ok(response) => {
+            attempt.status_code = Some(response.status().as_u16());
+            let body = response.text().await.unwrap_or_default();
+            attempt.response = Some(body.chars().take(RESPONSE_SNIPPET_LEN).collect());
+        }
+        Err(e) => attempt.error = Some(e.to_string()),
+    }
+    attempt.latency_ms = started.elapsed().as_millis() as u64;
+    attempt
+}
+
+/// Header carrying `t=<unix seconds>,v1=<hex>[,v1=<hex>…]`, one `v1` per
+/// active secret.
+pub const SIGNATURE_HEADER: &str = "LiteGate-Signature";
+/// Header carrying the event id, which is also signed.
+pub const EVENT_ID_HEADER: &str = "LiteGate-Event-Id";
+
+/// Active secrets of a comma-separated secret setting. During a rotation
+/// list the new secret next to the old one; every request is signed with
+/// each, so receivers holding either keep verifying.
+fn parse_secrets(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn hmac(secret: &str, parts: &[&[u8]]) -> Vec<u8> {
+    // HMAC accepts keys of any length
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// What a signature covers: `<t>.<event id>.<body>`, so neither the timestamp
+/// nor the id can be swapped on a captured body.
+fn signed_parts<'a>(t: &'a str, event_id: &'a str, body: &'a [u8]) -> [&'a [u8]; 5] {
+    [t.as_bytes(), b".", event_id.as_bytes(), b".", body]
+}
+
+fn signature_header(secrets: &[String], t: i64, event_id: &str, body: &[u8]) -> String {
+    let t = t.to_string();
+    let mut header = format!("t={t}");
+    for secret in secrets {
+        let sig = hmac(secret, &signed_parts(&t, event_id, body));
+        header.push_str(&format!(",v1={}", hex::encode(sig)));
+    }
+    header
+}
+
+/// Why [`verify_signature`] rejected a webhook.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SignatureError {
+    #[error("malformed signature header")]
+    Malformed,
+    #[error("signature timestamp outside the tolerance window")]
+    Expired,
+    #[error("no signature matches")]
+    Mismatch,
+}
+
+/// Checks a webhook request for receivers: `header` is the
+/// `LiteGate-Signature` value, `event_id` the `LiteGate-Event-Id` value and
+/// `body` the raw request body. Accepts the request if any `v1` matches any of
+/// `secrets` and its timestamp is within `tolerance` of now; returns that
+/// timestamp. Remember seen event ids for at least `tolerance` to reject
+/// replays within the window too.
+#[allow(dead_code)] // for receivers, through the library crate
+pub fn verify_signature(
+    header: &str,
+    event_id: &str,
+    body: &[u8],
+    secrets: &[&str],
+    tolerance: Duration,
+) -> Result<i64, SignatureError> {
+    let mut t = None;
+    let mut candidates = Vec::new();
+    for item in header.split(',') {
+        match item.trim().split_once('=') {
+            Some(("t", v)) => t = Some(v),
+            Some(("v1", v)) => {
+                candidates.push(hex::decode(v).map_err(|_| SignatureError::Malformed)?)
+            }
+            // unknown schemes are skipped so new ones can be added alongside
+            Some(_) => {}
+            None => return Err(SignatureError::Malformed),
+        }
+    }
+    let t_str = t.ok_or(SignatureError::Malformed)?;
+    let timestamp = t_str
+        .parse::<i64>()
+        .map_err(|_| SignatureError::Malformed)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    if (now - timestamp).unsigned_abs() > tolerance.as_secs() {
+        return Err(SignatureError::Expired);
+    }
+    for secret in secrets {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        for part in signed_parts(t_str, event_id, body) {
+            mac.update(part);
+        }
+        // constant-time comparison
+        if candidates
+            .iter()
+            .any(|c| mac.clone().verify_slice(c).is_ok())
+        {
+            return Ok(timestamp);
+        }
+    }
+    Err(SignatureError::Mismatch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = br#"{"event":"payment.confirmed"}"#;
+    const TOLERANCE: Duration = Duration::from_secs(300);
+
+    fn now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    #[test]
+    fn signed_header_verifies() {
+        let t = now();
+        let header = signature_header(&["s3cret".into()], t, "ev1", BODY);
+        assert_eq!(
+            verify_signature(&header, "ev1", BODY, &["s3cret"], TOLERANCE),
+            Ok(t)
+        );
+    }
+
+    #[test]
+    fn any_rotated_secret_verifies() {
+        let header = signature_header(&["new".into(), "old".into()], now(), "ev1", BODY);
+        assert!(verify_signature(&header, "ev1", BODY, &["old"], TOLERANCE).is_ok());
+        assert!(verify_signature(&header, "ev1", BODY, &["other", "new"], TOLERANCE).is_ok());
+        assert_eq!(
+            verify_signature(&header, "ev1", BODY, &["other"], TOLERANCE),
+            Err(SignatureError::Mismatch)
+        );
+    }
+
+    #[test]
+    fn timestamp_outside_tolerance_is_expired() {
+        for t in [now() - 301, now() + 301] {
+            let header = signature_header(&["s3cret".into()], t, "ev1", BODY);
+            assert_eq!(
+                verify_signature(&header, "ev1", BODY, &["s3cret"], TOLERANCE),
+                Err(SignatureError::Expired)
+            );
+        }
+    }
+
+    #[test]
+    fn changed_body_or_event_id_mismatches() {
+        let header = signature_header(&["s3cret".into()], now(), "ev1", BODY);
+        assert_eq!(
+            verify_signature(&header, "ev1", b"{}", &["s3cret"], TOLERANCE),
+            Err(SignatureError::Mismatch)
+        );
+        assert_eq!(
+            verify_signature(&header, "ev2", BODY, &["s3cret"], TOLERANCE),
+            Err(SignatureError::Mismatch)
+        );
+    }
+
+    #[test]
+    fn malformed_headers_are_rejected() {
+        let t = now();
+        for header in [
+            String::new(),
+            "garbage".into(),
+            format!("v1={}", "00".repeat(32)),
+            format!("t=soon,v1={}", "00".repeat(32)),
+            format!("t={t},v1=not-hex"),
+        ] {
+            assert_eq!(
+                verify_signature(&header, "ev1", BODY, &["s3cret"], TOLERANCE),
+                Err(SignatureError::Malformed),
+                "{header:?}"
+            );
+        }
+    }
+}